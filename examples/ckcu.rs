@@ -20,7 +20,9 @@ fn main() -> ! {
     // Hence the clock speed is actually changing
     rprintln!("Fibonnaci: {}", fibonacci_reccursive(32));
     rprintln!("Example: CKCU, done");
-    loop {}
+    loop {
+        cortex_m::asm::wfi();
+    }
 }
 
 fn fibonacci_reccursive(n: i32) -> u64 {
@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+use core::ops::DerefMut;
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::NVIC;
+use cortex_m::singleton;
+use cortex_m_rt::entry;
+use ht32f5xxxx_hal::gpio::{
+    gpioa::{PA4, PA5},
+    OpenDrain, Output, AF7,
+};
+use ht32f5xxxx_hal::pdma::{self, Channel, Transfer};
+use ht32f5xxxx_hal::{i2c::I2c, pac, pac::interrupt, prelude::*};
+use panic_rtt_target as _;
+use rtt_target::{rprintln, rtt_init_print};
+
+type Pins = (PA4<Output<OpenDrain>, AF7>, PA5<Output<OpenDrain>, AF7>);
+type Read = Transfer<&'static mut [u8; 1024], pdma::Ch4, I2c<pac::I2C0, Pins>>;
+
+static TRANSFER: Mutex<RefCell<Option<Read>>> = Mutex::new(RefCell::new(None));
+
+#[entry]
+fn main() -> ! {
+    rtt_init_print!();
+    rprintln!("Example: I2C DMA");
+    let dp = pac::Peripherals::take().unwrap();
+    let ckcu = dp.CKCU.constrain(dp.RSTCU);
+
+    let clocks = ckcu.configuration.ck_sys(8.mhz()).freeze();
+    let gpioa = dp.GPIOA.split();
+    let scl = gpioa.pa4.into_output_open_drain().into_alternate_af7();
+    let sda = gpioa.pa5.into_output_open_drain().into_alternate_af7();
+    let mut pdma = dp.PDMA.split();
+
+    let mut i2c = dp.I2C0.i2c(scl, sda, 400.khz(), &clocks);
+
+    // Set the address pointer of the EEPROM to 0, then read 1 KiB from there
+    i2c.write(0x50, &[0x00, 0x00]).unwrap();
+    let buffer = singleton!(: [u8; 1024] = [0; 1024]).unwrap();
+
+    // The interrupt sends the STOP as soon as the PDMA is done, so the bus
+    // is free again even if the transfer isn't waited for right away
+    pdma.ch4.listen(pdma::Event::TransferComplete);
+    let transfer = i2c.read_dma(0x50, buffer, pdma.ch4);
+    cortex_m::interrupt::free(|cs| {
+        *TRANSFER.borrow(cs).borrow_mut() = Some(transfer);
+    });
+    unsafe { NVIC::unmask(pac::Interrupt::PDMACH2_5) };
+
+    let mut spins = 0u32;
+    let transfer = loop {
+        // The CPU is free while the PDMA moves the data
+        spins += 1;
+        let transfer = cortex_m::interrupt::free(|cs| {
+            let mut transfer = TRANSFER.borrow(cs).borrow_mut();
+            match transfer.as_ref() {
+                Some(t) if t.is_done() => transfer.take(),
+                _ => None,
+            }
+        });
+        if let Some(transfer) = transfer {
+            break transfer;
+        }
+    };
+    let (result, buffer, _, mut i2c) = transfer.wait();
+    result.unwrap();
+    rprintln!("Done after {} spins, first bytes: {:02x?}", spins, &buffer[..16]);
+
+    // The bus was released by the interrupt, read the first bytes again
+    let mut check = [0; 16];
+    i2c.write_read(0x50, &[0x00, 0x00], &mut check).unwrap();
+    assert_eq!(check, buffer[..16]);
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+#[interrupt]
+fn PDMACH2_5() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(ref mut transfer) = TRANSFER.borrow(cs).borrow_mut().deref_mut() {
+            transfer.complete();
+        }
+    });
+}
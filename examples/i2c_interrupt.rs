@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+
+use core::cell::RefCell;
+use core::ops::DerefMut;
+use cortex_m::interrupt::Mutex;
+use cortex_m::peripheral::NVIC;
+use cortex_m::singleton;
+use cortex_m_rt::entry;
+use ht32f5xxxx_hal::gpio::{
+    gpioa::{PA4, PA5},
+    OpenDrain, Output, AF7,
+};
+use ht32f5xxxx_hal::{i2c, instance::Instance, pac, pac::interrupt, prelude::*};
+use panic_rtt_target as _;
+use rtt_target::{rprintln, rtt_init_print};
+
+type Pins = (PA4<Output<OpenDrain>, AF7>, PA5<Output<OpenDrain>, AF7>);
+
+static TRANSACTION: Mutex<RefCell<Option<i2c::I2cTransaction<'static, 'static, pac::I2C0, Pins>>>> =
+    Mutex::new(RefCell::new(None));
+
+#[entry]
+fn main() -> ! {
+    rtt_init_print!();
+    rprintln!("Example: I2C interrupt");
+    let dp = pac::Peripherals::take().unwrap();
+    let ckcu = dp.CKCU.constrain(dp.RSTCU);
+
+    let clocks = ckcu.configuration.ck_sys(8.mhz()).freeze();
+    let gpioa = dp.GPIOA.split();
+    let scl = gpioa.pa4.into_output_open_drain().into_alternate_af7();
+    let sda = gpioa.pa5.into_output_open_drain().into_alternate_af7();
+
+    let i2c = dp.I2C0.i2c(scl, sda, 100.khz(), &clocks);
+
+    // Set the colour of an LED controller, then read back its registers
+    let colour = singleton!(: [u8; 4] = [0x02, 0xff, 0x80, 0x00]).unwrap();
+    let register = singleton!(: [u8; 1] = [0x02]).unwrap();
+    let readback = singleton!(: [u8; 3] = [0; 3]).unwrap();
+    let requests = [
+        i2c::Request::write(0x30, colour),
+        i2c::Request::write_read(0x30, register, readback),
+    ];
+    let requests = singleton!(: [i2c::Request<'static>; 2] = requests).unwrap();
+
+    let transaction = i2c.start_transaction(requests);
+    cortex_m::interrupt::free(|cs| {
+        *TRANSACTION.borrow(cs).borrow_mut() = Some(transaction);
+    });
+    unsafe { NVIC::unmask(pac::I2C0::INTERRUPT) };
+
+    let mut scans = 0u32;
+    loop {
+        // The scanning loop keeps running while the bus is busy
+        scans += 1;
+        let done = cortex_m::interrupt::free(|cs| {
+            TRANSACTION
+                .borrow(cs)
+                .borrow()
+                .as_ref()
+                .is_none_or(|transaction| transaction.is_done())
+        });
+        if done {
+            rprintln!("Done after {} scans", scans);
+            loop {
+                cortex_m::asm::wfi();
+            }
+        }
+    }
+}
+
+#[interrupt]
+fn I2C0() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(ref mut transaction) = TRANSACTION.borrow(cs).borrow_mut().deref_mut() {
+            transaction.on_interrupt(|index, result| rprintln!("Request {}: {:?}", index, result));
+        }
+    });
+}
@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+
+use cortex_m_rt::entry;
+use ht32f5xxxx_hal::{i2c, pac, prelude::*};
+use panic_rtt_target as _;
+use rtt_target::{rprintln, rtt_init_print};
+
+#[entry]
+fn main() -> ! {
+    rtt_init_print!();
+    rprintln!("Example: I2C slave");
+    let dp = pac::Peripherals::take().unwrap();
+    let ckcu = dp.CKCU.constrain(dp.RSTCU);
+
+    ckcu.configuration.ck_sys(8.mhz()).freeze();
+    let gpioa = dp.GPIOA.split();
+    let scl = gpioa.pa4.into_output_open_drain().into_alternate_af7();
+    let sda = gpioa.pa5.into_output_open_drain().into_alternate_af7();
+
+    let mut slave = dp.I2C0.i2c_slave(scl, sda, i2c::config::SlaveConfig::new(0x42));
+
+    // A register file, the first byte the master writes selects the register
+    let mut registers = [0u8; 16];
+    let mut pointer: Option<usize> = None;
+    loop {
+        slave
+            .on_interrupt(|event| match event {
+                i2c::SlaveEvent::AddressMatched { read: false, .. } => {
+                    pointer = None;
+                    None
+                }
+                i2c::SlaveEvent::DataReceived(byte) => {
+                    match pointer {
+                        None => pointer = Some(byte as usize % registers.len()),
+                        Some(p) => {
+                            registers[p] = byte;
+                            pointer = Some((p + 1) % registers.len());
+                        }
+                    }
+                    None
+                }
+                i2c::SlaveEvent::DataRequested => {
+                    let p = pointer.unwrap_or(0);
+                    pointer = Some((p + 1) % registers.len());
+                    Some(registers[p])
+                }
+                _ => None,
+            })
+            .unwrap();
+    }
+}
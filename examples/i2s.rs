@@ -0,0 +1,40 @@
+#![no_std]
+#![no_main]
+
+use cortex_m_rt::entry;
+use ht32f5xxxx_hal::{i2s, pac, prelude::*};
+use panic_rtt_target as _;
+use rtt_target::{rprintln, rtt_init_print};
+
+#[entry]
+fn main() -> ! {
+    rtt_init_print!();
+    rprintln!("Example: I2S");
+    let dp = pac::Peripherals::take().unwrap();
+    let ckcu = dp.CKCU.constrain(dp.RSTCU);
+
+    let clocks = ckcu.configuration.ck_sys(48.mhz()).freeze();
+    let gpiob = dp.GPIOB.split();
+    let mclk = gpiob.pb2.into_output_push_pull().into_alternate_af10();
+    let bclk = gpiob.pb3.into_output_push_pull().into_alternate_af10();
+    let ws = gpiob.pb4.into_output_push_pull().into_alternate_af10();
+    let sdo = gpiob.pb5.into_output_push_pull().into_alternate_af10();
+    let sdi = gpiob.pb6.into_input_floating().into_alternate_af10();
+
+    let config = i2s::config::Config::default().sample_rate(16.khz());
+    let mut i2s = dp.I2S.i2s(bclk, ws, sdo, sdi, config, &clocks).unwrap().with_mclk(mclk);
+    rprintln!("Sample rate: {} Hz", i2s.sample_rate(&clocks).0);
+
+    // A 500 Hz square wave on both channels
+    let mut samples = [0u32; 64];
+    for (i, sample) in samples.iter_mut().enumerate() {
+        let level: i16 = if i < 32 { 0x2000 } else { -0x2000 };
+        *sample = level as u16 as u32;
+    }
+
+    loop {
+        for sample in samples.iter() {
+            i2s.write_all(&[*sample, *sample]).unwrap();
+        }
+    }
+}
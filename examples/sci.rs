@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+
+use cortex_m_rt::entry;
+use ht32f5xxxx_hal::{pac, prelude::*, sci};
+use panic_rtt_target as _;
+use rtt_target::{rprintln, rtt_init_print};
+
+#[entry]
+fn main() -> ! {
+    rtt_init_print!();
+    rprintln!("Example: SCI");
+    let dp = pac::Peripherals::take().unwrap();
+    let ckcu = dp.CKCU.constrain(dp.RSTCU);
+
+    let clocks = ckcu.configuration.ck_sys(8.mhz()).freeze();
+    let gpioa = dp.GPIOA.split();
+    let clk = gpioa.pa6.into_output_push_pull().into_alternate_af8();
+    let dio = gpioa.pa7.into_output_open_drain().into_alternate_af8();
+    let mut rst = gpioa.pa8.into_output_push_pull();
+
+    let mut card = dp.SCI.sci(clk, dio, sci::config::Config::default(), &clocks).unwrap();
+
+    let atr = card.activate(&mut rst).unwrap();
+    rprintln!("ATR: {:02x?}", atr.bytes());
+
+    // SELECT the master file
+    let select = sci::Command {
+        cla: 0x00,
+        ins: 0xa4,
+        p1: 0x00,
+        p2: 0x00,
+        data: &[0x3f, 0x00],
+        le: None,
+    };
+    let mut response = [0; 256];
+    let (len, sw) = card.transmit(&select, &mut response).unwrap();
+    rprintln!("Response: {:02x?} {:?}", &response[..len], sw);
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
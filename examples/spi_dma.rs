@@ -0,0 +1,38 @@
+#![no_std]
+#![no_main]
+
+use cortex_m::singleton;
+use cortex_m_rt::entry;
+use ht32f5xxxx_hal::{pac, prelude::*, spi};
+use panic_rtt_target as _;
+use rtt_target::{rprintln, rtt_init_print};
+
+#[entry]
+fn main() -> ! {
+    rtt_init_print!();
+    rprintln!("Example: SPI DMA");
+    let dp = pac::Peripherals::take().unwrap();
+    let ckcu = dp.CKCU.constrain(dp.RSTCU);
+
+    let clocks = ckcu.configuration.ck_sys(8.mhz()).freeze();
+    let gpioa = dp.GPIOA.split();
+    let miso = gpioa.pa2.into_input_floating().into_alternate_af5();
+    let sck = gpioa.pa0.into_output_push_pull().into_alternate_af5();
+    let mosi = gpioa.pa1.into_output_push_pull().into_alternate_af5();
+    let pdma = dp.PDMA.split();
+
+    let spi: spi::Spi<_, u8> = dp.SPI1.spi(sck, miso, mosi, spi::MODE_0, 1.mhz(), &clocks);
+
+    let tx_buffer = singleton!(: [u8; 4] = [0x11, 0x22, 0x33, 0x44]).unwrap();
+    let rx_buffer = singleton!(: [u8; 4] = [0; 4]).unwrap();
+
+    let transfer = spi.transfer_dma(tx_buffer, rx_buffer, pdma.ch2, pdma.ch3);
+    // The CPU is free to do something else here
+    let (result, (_, rx_buffer), _, _) = transfer.wait();
+    result.unwrap();
+    rprintln!("Received: {:02x?}", rx_buffer);
+
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
@@ -98,6 +98,8 @@ pub struct Clocks {
     pub(crate) stclk: Hertz,
     /// The frequency for HCLK, aka the AHB bus
     pub(crate) hclk: Hertz,
+    /// The frequency for PCLK, the clock of the APB peripherals
+    pub(crate) pclk: Hertz,
 }
 
 impl Configuration {
@@ -238,7 +240,7 @@ impl Configuration {
                 // VCO_out = CK_in * (NF1*NF2)/2 = CK_in * (4*NF2)/2
                 // and VCO_out must be between 48 and 96 Mhz
                 let vco_out = hso.0 * (4 * nf2) / 2;
-                if vco_out >= 48_000_000 && vco_out <= 96_000_000 {
+                if (48_000_000..=96_000_000).contains(&vco_out) {
                     for no2 in &[1, 2, 4, 8] {
                         let current_divider = nf2 as f32 / *no2 as f32;
 
@@ -398,6 +400,9 @@ impl Configuration {
             ck_sys,
             stclk,
             hclk,
+            // The APB peripheral prescalers are left at their reset value
+            // of 1, refer to User Manual page 83
+            pclk: hclk,
         }
     }
 }
@@ -88,7 +88,7 @@ pub enum GpioCurrent {
 }
 
 impl GpioCurrent {
-    fn to_bits(&self) -> u8 {
+    fn to_bits(self) -> u8 {
         match self {
             Self::MA4 => 0b00,
             Self::MA8 => 0b01,
@@ -502,7 +502,7 @@ macro_rules! gpio {
     }
 }
 
-#[cfg(any(feature = "ht32f52342_52"))]
+#[cfg(feature = "ht32f52342_52")]
 gpio!(GPIOA, gpioa, PA, parst, paen, gpioa_doutr, gpioa_dinr, gpioa_drvr, gpioa_dircr, gpioa_pur, gpioa_pdr, gpioa_iner, gpioa_odr, [
     PA0: (pa0, 0, Input<Disabled>, AF0, dout0, din0, dv0, dir0, pu0, pd0, inen0, od0, cfg0, afio_gpacfglr),
     PA1: (pa1, 1, Input<Disabled>, AF0, dout1, din1, dv1, dir1, pu1, pd1, inen1, od1, cfg1, afio_gpacfglr),
@@ -526,7 +526,7 @@ gpio!(GPIOA, gpioa, PA, parst, paen, gpioa_doutr, gpioa_dinr, gpioa_drvr, gpioa_
     PA15: (pa15, 15, Input<Disabled>, AF0, dout15, din15, dv15, dir15, pu15, pd15, inen15, od15, cfg15, afio_gpacfghr),
 ]);
 
-#[cfg(any(feature = "ht32f52342_52"))]
+#[cfg(feature = "ht32f52342_52")]
 gpio!(GPIOB, gpiob, PB, pbrst, pben, gpiob_doutr, gpiob_dinr, gpiob_drvr, gpiob_dircr, gpiob_pur, gpiob_pdr, gpiob_iner, gpiob_odr, [
     PB0: (pb0, 0, Input<Disabled>, AF0, dout0, din0, dv0, dir0, pu0, pd0, inen0, od0, cfg0, afio_gpbcfglr),
     PB1: (pb1, 1, Input<Disabled>, AF0, dout1, din1, dv1, dir1, pu1, pd1, inen1, od1, cfg1, afio_gpbcfglr),
@@ -546,7 +546,7 @@ gpio!(GPIOB, gpiob, PB, pbrst, pben, gpiob_doutr, gpiob_dinr, gpiob_drvr, gpiob_
     PB15: (pb15, 15, Input<Disabled>, AF0, dout15, din15, dv15, dir15, pu15, pd15, inen15, od15, cfg15, afio_gpbcfghr),
 ]);
 
-#[cfg(any(feature = "ht32f52342_52"))]
+#[cfg(feature = "ht32f52342_52")]
 gpio!(GPIOC, gpioc, PC, pcrst, pcen, gpioc_doutr, gpioc_dinr, gpioc_drvr, gpioc_dircr, gpioc_pur, gpioc_pdr, gpioc_iner, gpioc_odr, [
     PC0: (pc0, 0, Input<Disabled>, AF0, dout0, din0, dv0, dir0, pu0, pd0, inen0, od0, cfg0, afio_gpccfglr),
     PC1: (pc1, 1, Input<Disabled>, AF0, dout1, din1, dv1, dir1, pu1, pd1, inen1, od1, cfg1, afio_gpccfglr),
@@ -567,7 +567,7 @@ gpio!(GPIOC, gpioc, PC, pcrst, pcen, gpioc_doutr, gpioc_dinr, gpioc_drvr, gpioc_
 ]);
 
 // Block D only has 4 pins
-#[cfg(any(feature = "ht32f52342_52"))]
+#[cfg(feature = "ht32f52342_52")]
 gpio!(GPIOD, gpiod, PD, pdrst, pden, gpiod_doutr, gpiod_dinr, gpiod_drvr, gpiod_dircr, gpiod_pur, gpiod_pdr, gpiod_iner, gpiod_odr, [
     PD0: (pd0, 0, Input<Disabled>, AF0, dout0, din0, dv0, dir0, pu0, pd0, inen0, od0, cfg0, afio_gpdcfglr),
     PD1: (pd1, 1, Input<Disabled>, AF0, dout1, din1, dv1, dir1, pu1, pd1, inen1, od1, cfg1, afio_gpdcfglr),
@@ -1,20 +1,19 @@
 //! Inter Integrated Circuit implementation
 use crate::ckcu::Clocks;
-use crate::gpio::{
-    gpioa::{PA0, PA1, PA14, PA15, PA4, PA5},
-    gpiob::{PB0, PB1, PB15, PB7, PB8},
-    gpioc::{PC0, PC12, PC13, PC14, PC15, PC4, PC5, PC6, PC7},
-    gpiod::PD0,
-    OpenDrain, Output, AF7,
-};
 use crate::hal::blocking::i2c::{Read, Write, WriteRead};
-use crate::ht32::{CKCU, I2C0, I2C1, RSTCU};
+use crate::hal::digital::v2::OutputPin;
+use crate::hal1;
+use crate::ht32::{i2c0::RegisterBlock, I2C0, I2C1};
+use crate::instance::Instance;
+use crate::pdma::{self, ReadBuffer, Transfer, TransferPayload, WriteBuffer};
 use crate::time::Hertz;
-use crate::time::U32Ext;
-use core::convert::TryInto;
+use core::convert::Infallible;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+pub mod smbus;
 
 #[non_exhaustive]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Error {
     /// Arbitration error
     Arbitration,
@@ -22,9 +21,22 @@ pub enum Error {
     Bus,
     /// The slave didn't send ACK
     NotAcknowledge,
+    /// The bus did not make progress within the configured timeout, e.g.
+    /// because a slave stretches the clock forever
+    Timeout,
+    /// The PDMA failed to access a buffer
+    Dma,
+    /// Another master kept the bus busy for longer than allowed
+    BusBusy,
 }
 
-#[derive(Debug)]
+impl From<pdma::TransferError> for Error {
+    fn from(_: pdma::TransferError) -> Error {
+        Error::Dma
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub enum Event {
     RxBufferFull,
     DataRegisterEmtpyTransmitter,
@@ -34,27 +46,594 @@ pub enum Event {
     ArbitrationLoss,
     StopConditionDetected,
     StartConditionTransmit,
+    AddressMatched,
+    GeneralCall,
 }
 
+impl hal1::i2c::Error for Error {
+    fn kind(&self) -> hal1::i2c::ErrorKind {
+        match self {
+            Error::Arbitration => hal1::i2c::ErrorKind::ArbitrationLoss,
+            Error::Bus => hal1::i2c::ErrorKind::Bus,
+            Error::NotAcknowledge => hal1::i2c::ErrorKind::NoAcknowledge(hal1::i2c::NoAcknowledgeSource::Unknown),
+            Error::Timeout | Error::Dma | Error::BusBusy => hal1::i2c::ErrorKind::Other,
+        }
+    }
+}
+
+pub mod config {
+    use crate::time::{Hertz, U32Ext};
+
+    /// The offset the hardware adds to SHPG and SLPG, refer to User Manual
+    /// page 470
+    const SCL_OFFSET: u32 = 6;
+
+    /// Limits of a bus speed class in ns, refer to table 10 of the I2C
+    /// specification
+    struct Mode {
+        low_min: u32,
+        high_min: u32,
+        rise_max: u32,
+        fall_max: u32,
+        /// Whether SCL low should be twice as long as high
+        fast: bool,
+    }
+
+    const STANDARD: Mode = Mode {
+        low_min: 4700,
+        high_min: 4000,
+        rise_max: 1000,
+        fall_max: 300,
+        fast: false,
+    };
+
+    const FAST: Mode = Mode {
+        low_min: 1300,
+        high_min: 600,
+        rise_max: 300,
+        fall_max: 300,
+        fast: true,
+    };
+
+    const FAST_PLUS: Mode = Mode {
+        low_min: 500,
+        high_min: 260,
+        rise_max: 120,
+        fall_max: 120,
+        fast: true,
+    };
+
+    pub struct Config {
+        /// The desired SCL frequency, the closest one that is not higher
+        /// will be chosen. Up to 100 kHz is Standard-mode, up to 400 kHz
+        /// Fast-mode and up to 1 MHz Fast-mode Plus.
+        pub frequency: Hertz,
+        /// Rise time of SCL in ns, it is determined by the pull up and the
+        /// bus capacitance
+        pub rise_time: u32,
+        /// Fall time of SCL in ns
+        pub fall_time: u32,
+        /// How long a blocking operation waits for the bus to make
+        /// progress, e.g. while a slave stretches SCL
+        pub timeout: Timeout,
+    }
+
+    impl Config {
+        pub fn frequency<F>(mut self, frequency: F) -> Self
+        where
+            F: Into<Hertz>,
+        {
+            self.frequency = frequency.into();
+            self
+        }
+
+        pub fn rise_time(mut self, ns: u32) -> Self {
+            self.rise_time = ns;
+            self
+        }
+
+        pub fn fall_time(mut self, ns: u32) -> Self {
+            self.fall_time = ns;
+            self
+        }
+
+        pub fn timeout(mut self, timeout: Timeout) -> Self {
+            self.timeout = timeout;
+            self
+        }
+
+        /// Computes the SCL generator values for the given PCLK
+        pub fn timing(&self, pclk: Hertz) -> Result<Timing, InvalidConfig> {
+            let mode = if self.frequency.0 == 0 {
+                return Err(InvalidConfig::FrequencyTooLow);
+            } else if self.frequency.0 <= 100_000 {
+                STANDARD
+            } else if self.frequency.0 <= 400_000 {
+                FAST
+            } else if self.frequency.0 <= 1_000_000 {
+                FAST_PLUS
+            } else {
+                return Err(InvalidConfig::FrequencyTooHigh);
+            };
+            if self.rise_time > mode.rise_max {
+                return Err(InvalidConfig::RiseTimeTooLong);
+            }
+            if self.fall_time > mode.fall_max {
+                return Err(InvalidConfig::FallTimeTooLong);
+            }
+
+            let pclk = pclk.0 as u64;
+            // Rounded up so the bus rather gets slower than faster
+            let cycles = |ns: u32| (ns as u64 * pclk).div_ceil(1_000_000_000);
+
+            // SCL_low = 1/pclk * (SLPG + d)
+            // SCL_high = 1/pclk * (SHPG + d)
+            // The high phase only starts once SCL actually rose, so the rise
+            // time adds to the period. The fall time eats into the low phase
+            // as it is measured from the falling edge.
+            let rise = cycles(self.rise_time);
+            let period = pclk.div_ceil(self.frequency.0 as u64);
+            let period = period.saturating_sub(rise);
+
+            let low_min = cycles(mode.low_min + self.fall_time);
+            let high_min = cycles(mode.high_min);
+            // In Fast-mode and Fast-mode Plus SCL_low = 2 * SCL_high,
+            // otherwise they are equal, refer to I2C spec page 48
+            let low = if mode.fast {
+                (period * 2).div_ceil(3)
+            } else {
+                period.div_ceil(2)
+            };
+            let low = low.max(low_min).max(SCL_OFFSET as u64);
+            let high = period.saturating_sub(low).max(high_min).max(SCL_OFFSET as u64);
+
+            let slpg = low - SCL_OFFSET as u64;
+            let shpg = high - SCL_OFFSET as u64;
+            if slpg > u16::MAX as u64 || shpg > u16::MAX as u64 {
+                return Err(InvalidConfig::FrequencyTooLow);
+            }
+
+            let scl = (pclk * 1_000_000_000) / ((low + high) * 1_000_000_000 + self.rise_time as u64 * pclk);
+            Ok(Timing {
+                shpg: shpg as u16,
+                slpg: slpg as u16,
+                scl: Hertz(scl as u32),
+            })
+        }
+    }
+
+    impl Default for Config {
+        fn default() -> Config {
+            Config {
+                frequency: 100.khz().into(),
+                rise_time: 0,
+                fall_time: 0,
+                // Far longer than a byte takes even in Standard-mode, a
+                // slave that stretches SCL that long is considered stuck
+                timeout: Timeout::Micros(10_000),
+            }
+        }
+    }
+
+    /// Values of the SCL generator, as computed by `Config::timing`
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Timing {
+        pub shpg: u16,
+        pub slpg: u16,
+        /// The SCL frequency that is achieved with these values
+        pub scl: Hertz,
+    }
+
+    /// How long a master waits for the bus before failing with
+    /// `Error::Timeout`
+    #[derive(Debug, Clone, Copy)]
+    pub enum Timeout {
+        Disabled,
+        Micros(u32),
+    }
+
+    impl Timeout {
+        /// Computes the prescaler and the counter value for the given PCLK,
+        /// `None` if the timeout is disabled
+        pub fn register(&self, pclk: Hertz) -> Result<Option<(u8, u16)>, InvalidConfig> {
+            let micros = match self {
+                Timeout::Disabled => return Ok(None),
+                Timeout::Micros(micros) => *micros,
+            };
+
+            // The counter runs at PCLK / 2^PSC according to User Manual
+            // page 467
+            let cycles = (pclk.0 as u64 * micros as u64).div_ceil(1_000_000);
+            let (psc, tout) = (0..8u8)
+                .map(|psc| (psc, (cycles + (1 << psc) - 1) >> psc))
+                .find(|(_, tout)| *tout <= u16::MAX as u64)
+                .ok_or(InvalidConfig::TimeoutTooLong)?;
+            Ok(Some((psc, tout.max(1) as u16)))
+        }
+    }
+
+    /// Behaviour on a bus that is shared with other masters
+    #[derive(Debug, Clone, Copy)]
+    pub struct MultiMaster {
+        /// How long to wait for the bus to become free before failing with
+        /// `Error::BusBusy`, in µs
+        pub bus_free_timeout: u32,
+        /// How often a transfer is repeated after the arbitration was lost
+        pub retries: u8,
+        /// How long to back off before a retry, in µs
+        pub retry_delay: u32,
+    }
+
+    impl MultiMaster {
+        pub fn bus_free_timeout(mut self, micros: u32) -> Self {
+            self.bus_free_timeout = micros;
+            self
+        }
+
+        pub fn retries(mut self, retries: u8) -> Self {
+            self.retries = retries;
+            self
+        }
+
+        pub fn retry_delay(mut self, micros: u32) -> Self {
+            self.retry_delay = micros;
+            self
+        }
+    }
+
+    impl Default for MultiMaster {
+        fn default() -> MultiMaster {
+            MultiMaster {
+                bus_free_timeout: 1000,
+                retries: 3,
+                retry_delay: 100,
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum InvalidConfig {
+        /// The timeout is longer than the counter allows
+        TimeoutTooLong,
+        /// The SCL frequency is above 1 MHz
+        FrequencyTooHigh,
+        /// The SCL frequency is lower than SHPG and SLPG allow
+        FrequencyTooLow,
+        /// The rise time is above the limit of the bus speed class
+        RiseTimeTooLong,
+        /// The fall time is above the limit of the bus speed class
+        FallTimeTooLong,
+    }
+
+    /// Addressing of an `I2cSlave`
+    pub struct SlaveConfig {
+        /// The 7 bit own address
+        pub address: u8,
+        /// Address bits set in the mask are ignored when matching, so the
+        /// slave can answer to several addresses
+        pub mask: u8,
+        /// Whether to answer the general call address 0
+        pub general_call: bool,
+    }
+
+    impl SlaveConfig {
+        pub fn new(address: u8) -> Self {
+            SlaveConfig {
+                address,
+                mask: 0,
+                general_call: false,
+            }
+        }
+
+        pub fn mask(mut self, mask: u8) -> Self {
+            self.mask = mask;
+            self
+        }
+
+        pub fn general_call(mut self) -> Self {
+            self.general_call = true;
+            self
+        }
+    }
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const MHZ: u32 = 1_000_000;
+
+        fn timing(pclk: u32, frequency: u32) -> Result<Timing, InvalidConfig> {
+            Config::default().frequency(Hertz(frequency)).timing(Hertz(pclk))
+        }
+
+        #[test]
+        fn default_timeout() {
+            // 10 ms at 48 MHz are 480000 cycles, which need a prescaler of 8
+            let register = Config::default().timeout.register(Hertz(48 * MHZ)).unwrap();
+            assert_eq!(register, Some((3, 60_000)));
+        }
+
+        #[test]
+        fn timeout_range() {
+            assert_eq!(Timeout::Disabled.register(Hertz(48 * MHZ)).unwrap(), None);
+            assert_eq!(Timeout::Micros(0).register(Hertz(48 * MHZ)).unwrap(), Some((0, 1)));
+            assert!(Timeout::Micros(200_000).register(Hertz(48 * MHZ)).is_err());
+        }
+
+        #[test]
+        fn standard_mode() {
+            let t = timing(48 * MHZ, 100_000).unwrap();
+            assert_eq!(t.slpg, 234);
+            assert_eq!(t.shpg, 234);
+            assert_eq!(t.scl, Hertz(100_000));
+        }
+
+        #[test]
+        fn fast_mode() {
+            let t = timing(48 * MHZ, 400_000).unwrap();
+            assert_eq!(t.slpg, 74);
+            assert_eq!(t.shpg, 34);
+            assert_eq!(t.scl, Hertz(400_000));
+        }
+
+        #[test]
+        fn fast_mode_plus() {
+            let t = timing(48 * MHZ, 1_000_000).unwrap();
+            assert_eq!(t.slpg, 26);
+            assert_eq!(t.shpg, 10);
+            assert_eq!(t.scl, Hertz(1_000_000));
+        }
+
+        #[test]
+        fn clamped_to_minimum_phases() {
+            // 8 cycles per period are not enough for the 500 ns + 260 ns of
+            // Fast-mode Plus, so the bus gets slower
+            let t = timing(8 * MHZ, 1_000_000).unwrap();
+            assert_eq!(t.slpg, 0);
+            assert_eq!(t.shpg, 0);
+            assert_eq!(t.scl, Hertz(666_666));
+        }
+
+        #[test]
+        fn rise_time_counts_into_period() {
+            let t = Config::default()
+                .frequency(Hertz(100_000))
+                .rise_time(1000)
+                .timing(Hertz(48 * MHZ))
+                .unwrap();
+            assert!(t.scl.0 <= 100_000);
+            assert!(t.slpg < 234);
+        }
+
+        #[test]
+        fn rise_and_fall_limits() {
+            let config = Config::default().frequency(Hertz(400_000));
+            assert!(config.rise_time(300).fall_time(300).timing(Hertz(48 * MHZ)).is_ok());
+
+            let config = Config::default().frequency(Hertz(400_000));
+            assert!(matches!(
+                config.rise_time(301).timing(Hertz(48 * MHZ)),
+                Err(InvalidConfig::RiseTimeTooLong)
+            ));
+
+            let config = Config::default().frequency(Hertz(1_000_000));
+            assert!(matches!(
+                config.fall_time(121).timing(Hertz(48 * MHZ)),
+                Err(InvalidConfig::FallTimeTooLong)
+            ));
+        }
+
+        #[test]
+        fn frequency_limits() {
+            assert!(matches!(timing(48 * MHZ, 0), Err(InvalidConfig::FrequencyTooLow)));
+            assert!(matches!(
+                timing(48 * MHZ, 1_000_001),
+                Err(InvalidConfig::FrequencyTooHigh)
+            ));
+        }
+
+        #[test]
+        fn slpg_overflow() {
+            // 48 MHz / 300 Hz needs a low phase of 80000 cycles
+            assert!(matches!(timing(48 * MHZ, 300), Err(InvalidConfig::FrequencyTooLow)));
+            assert!(timing(48 * MHZ, 400).is_ok());
+        }
+    }
+}
+
+/// The address of a slave, plain `u8`s are taken as 7 bit addresses
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Address {
+    SevenBit(u8),
+    TenBit(u16),
+}
+
+impl From<u8> for Address {
+    fn from(address: u8) -> Self {
+        Address::SevenBit(address)
+    }
+}
+
+/// What happened on the bus while we act as slave
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlaveEvent {
+    /// A master addressed us, `read` means it wants to read from us
+    AddressMatched { read: bool, general_call: bool },
+    /// The master wrote a byte to us
+    DataReceived(u8),
+    /// The master wants to read the next byte, answer with `respond`
+    DataRequested,
+    /// The transfer is over
+    Stop,
+}
+
+/// A pin of the bus that can temporarily be switched to GPIO to free a
+/// stuck bus
+pub trait BusPin: Sized {
+    type Gpio: OutputPin<Error = Infallible>;
+
+    fn into_gpio(self) -> Self::Gpio;
+
+    fn from_gpio(gpio: Self::Gpio) -> Self;
+}
+
+pub trait TxChannel<I2C>: pdma::Channel + pdma::Channels {}
+pub trait RxChannel<I2C>: pdma::Channel + pdma::Channels {}
+
 pub trait PinScl<I2C> {}
 
 pub trait PinSda<I2C> {}
 
+/// An I2C master, `PINS` are the SCL and SDA pins if it was created with
+/// one of the checked constructors
+#[derive(Debug)]
+pub struct I2c<I2C, PINS = ()> {
+    i2c: I2C,
+    pins: PINS,
+    scl: Hertz,
+    /// Outcome of a DMA transfer that was already ended by
+    /// `Transfer::complete`, reported by `Transfer::wait`
+    dma_result: Result<(), Error>,
+    multi_master: Option<config::MultiMaster>,
+    /// HCLK cycles per µs, to wait in multi master mode
+    cycles_per_us: u32,
+}
+
+impl<I2C, PINS> hal1::i2c::ErrorType for I2c<I2C, PINS> {
+    type Error = Error;
+}
+
+/// An I2C peripheral that answers to its own address, meant to be driven
+/// from the I2C interrupt
 #[derive(Debug)]
-pub struct I2c<I2C> {
+pub struct I2cSlave<I2C> {
     i2c: I2C,
+    /// The master NACKed the last byte we sent, it doesn't want any more
+    nacked: bool,
+}
+
+/// What a `Request` does on the bus
+#[derive(Debug)]
+pub enum Operation<'a> {
+    Write(&'a [u8]),
+    Read(&'a mut [u8]),
+    /// A write followed by a read after a repeated START
+    WriteRead(&'a [u8], &'a mut [u8]),
+}
+
+/// A single transfer that is queued in an `I2cTransaction`
+#[derive(Debug)]
+pub struct Request<'a> {
+    pub address: Address,
+    pub operation: Operation<'a>,
+    /// The outcome, once the request is done
+    pub result: Option<Result<(), Error>>,
+}
+
+impl<'a> Request<'a> {
+    pub fn write<A: Into<Address>>(address: A, bytes: &'a [u8]) -> Self {
+        Self::new(address.into(), Operation::Write(bytes))
+    }
+
+    pub fn read<A: Into<Address>>(address: A, buffer: &'a mut [u8]) -> Self {
+        Self::new(address.into(), Operation::Read(buffer))
+    }
+
+    pub fn write_read<A: Into<Address>>(address: A, bytes: &'a [u8], buffer: &'a mut [u8]) -> Self {
+        Self::new(address.into(), Operation::WriteRead(bytes, buffer))
+    }
+
+    fn new(address: Address, operation: Operation<'a>) -> Self {
+        Request {
+            address,
+            operation,
+            result: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    /// The number of bytes that were written so far
+    Writing(usize),
+    /// The number of bytes that were received so far
+    Reading(usize),
+    Done,
+}
+
+/// The events an `I2cTransaction` is driven by
+const TRANSACTION_EVENTS: [Event; 5] = [
+    Event::DataRegisterEmtpyTransmitter,
+    Event::DataRegisterEmptyReceiver,
+    Event::ReceivedNotAcknowledge,
+    Event::ArbitrationLoss,
+    Event::BusError,
+];
+
+/// Runs a queue of requests one after another from the I2C interrupt,
+/// so the CPU doesn't have to wait for the bus
+#[derive(Debug)]
+pub struct I2cTransaction<'r, 'b, I2C, PINS = ()> {
+    i2c: I2c<I2C, PINS>,
+    requests: &'r mut [Request<'b>],
+    /// Index of the request in progress
+    current: usize,
+    state: State,
 }
 
 pub trait I2cExt<I2C>: Sized {
-    fn i2c<SCL, SDA, F>(self, scl: SCL, sda: SDA, freq: F, clocks: &Clocks) -> I2c<I2C>
+    /// Panics if `freq` can't be reached, use `i2c_with_config` to handle
+    /// the error instead
+    fn i2c<SCL, SDA, F>(self, scl: SCL, sda: SDA, freq: F, clocks: &Clocks) -> I2c<I2C, (SCL, SDA)>
     where
         SCL: PinScl<I2C>,
         SDA: PinSda<I2C>,
         F: Into<Hertz>;
 
+    /// Panics if `freq` can't be reached, use `i2c_with_config_unchecked`
+    /// to handle the error instead
     fn i2c_unchecked<F>(self, freq: F, clocks: &Clocks) -> I2c<I2C>
     where
         F: Into<Hertz>;
+
+    fn i2c_with_config<SCL, SDA>(
+        self,
+        scl: SCL,
+        sda: SDA,
+        config: config::Config,
+        clocks: &Clocks,
+    ) -> Result<I2c<I2C, (SCL, SDA)>, config::InvalidConfig>
+    where
+        SCL: PinScl<I2C>,
+        SDA: PinSda<I2C>;
+
+    fn i2c_with_config_unchecked(
+        self,
+        config: config::Config,
+        clocks: &Clocks,
+    ) -> Result<I2c<I2C>, config::InvalidConfig>;
+
+    fn i2c_slave<SCL, SDA>(self, scl: SCL, sda: SDA, config: config::SlaveConfig) -> I2cSlave<I2C>
+    where
+        SCL: PinScl<I2C>,
+        SDA: PinSda<I2C>;
+
+    fn i2c_slave_unchecked(self, config: config::SlaveConfig) -> I2cSlave<I2C>;
+}
+
+/// Enables or disables the interrupt of `event`, shared by master and slave
+fn set_interrupt(i2c: &RegisterBlock, event: Event, enable: bool) {
+    match event {
+        Event::RxBufferFull => i2c.i2c_ier.modify(|_, w| w.rxbfie().bit(enable)),
+        Event::DataRegisterEmtpyTransmitter => i2c.i2c_ier.modify(|_, w| w.txdeie().bit(enable)),
+        Event::DataRegisterEmptyReceiver => i2c.i2c_ier.modify(|_, w| w.rxdneie().bit(enable)),
+        Event::BusError => i2c.i2c_ier.modify(|_, w| w.buserrie().bit(enable)),
+        Event::ReceivedNotAcknowledge => i2c.i2c_ier.modify(|_, w| w.rxnackie().bit(enable)),
+        Event::ArbitrationLoss => i2c.i2c_ier.modify(|_, w| w.arblosie().bit(enable)),
+        Event::StopConditionDetected => i2c.i2c_ier.modify(|_, w| w.stoie().bit(enable)),
+        Event::StartConditionTransmit => i2c.i2c_ier.modify(|_, w| w.staie().bit(enable)),
+        Event::AddressMatched => i2c.i2c_ier.modify(|_, w| w.adrsie().bit(enable)),
+        Event::GeneralCall => i2c.i2c_ier.modify(|_, w| w.gcsie().bit(enable)),
+    }
 }
 
 macro_rules! busy_wait {
@@ -70,6 +649,10 @@ macro_rules! busy_wait {
                 return Err(Error::NotAcknowledge);
             } else if status.buserr().bit_is_set() {
                 return Err(Error::Bus);
+            } else if status.toutf().bit_is_set() {
+                // write 1 to clear
+                $i2c.i2c_sr.write(|w| w.toutf().set_bit());
+                return Err(Error::Timeout);
             } else {
                 // no error
             }
@@ -78,110 +661,511 @@ macro_rules! busy_wait {
 }
 
 macro_rules! i2c {
-    ($($I2CX:ident: ($i2cX:ident, $i2cXen:ident, $i2cXrst:ident),)+) => {
+    ($($I2CX:ident: $i2cX:ident,)+) => {
         $(
             impl I2c<$I2CX> {
                 /// Creates a new I2C peripheral
-                pub fn $i2cX<F>(
+                pub fn $i2cX(
                     i2c: $I2CX,
-                    freq: F,
+                    config: config::Config,
                     clocks: &Clocks,
-                ) -> Self where
-                    F: Into<Hertz>
-                {
-                    let freq = freq.into();
-
-                    assert!(freq <= 1.mhz().into());
-
-                    // SCL_low = 1/pclk * (SLPG + d)
-                    // SCL_high = 1/pclk * (SHPG + d)
-                    // where d = 6
-                    // T_SCL = SCL_low + SCL_high
-                    //
-                    // Refer to User Manual page 470 and 471
-                    // Note that PCLK = HCLK in this case as we didn't choose as
-                    // PCLK divider
-                    let (shpg, slpg) = if freq > 100.khz().into() {
-                        // We are in Fast-mode or Fast-mode Plus, this means
-                        // SCL_low = 2 * SCL_high, refer to I2C spec page 48
-                        // -> SCL_low = 2/3 SCL
-                        // -> SLPG = (2 * PCLK ) / (3 * SCL) - 6
-                        let slpg = ((2 * clocks.hclk.0) / (3 * freq.0)) - 6;
-
-                        // 1/pclk * ( SLPG + d ) = 2/pclk * (SHPG + d)
-                        // -> SHPG = (SLPG - d)/2
-                        // + 1 serves as a correction factor so SCL gets slower
-                        // rather than larger as freq
-                        let shpg = ((slpg - 6) / 2) + 1;
-                        (shpg, slpg)
-                    } else {
-                        // We are in Standard mode, this means
-                        // SCL_low = SCL_high, refer to I2C spec page 48
-                        // -> SLPG = SHPG = pclk / (2*SCL) - 6
-                        let scl_div = ((clocks.hclk.0) / (2 * freq.0)) - 6;
-                        (scl_div, scl_div)
-                    };
+                ) -> Result<Self, config::InvalidConfig> {
+                    let timing = config.timing(clocks.pclk)?;
+                    let timeout = config.timeout.register(clocks.pclk)?;
 
-                    let rstcu = unsafe { &*RSTCU::ptr() };
-                    let ckcu = unsafe { &*CKCU::ptr() };
                     // reset the I2C port before using it
-                    rstcu.rstcu_apbprstr0.modify(|_, w| w.$i2cXrst().set_bit());
-                    // enable the AHB clock for the I2C port
-                    ckcu.ckcu_apbccr0.modify(|_, w| w.$i2cXen().set_bit());
+                    $I2CX::reset();
+                    // enable the APB clock for the I2C port
+                    $I2CX::enable();
 
-                    // Configure the SCL clock values
-                    i2c.i2c_shpgr.modify(|_, w| unsafe { w.shpg().bits(shpg.try_into().unwrap()) });
-                    i2c.i2c_slpgr.modify(|_, w| unsafe { w.slpg().bits(slpg.try_into().unwrap()) });
+                    // Configure the SCL clock values, refer to User Manual
+                    // page 470 and 471
+                    i2c.i2c_shpgr.modify(|_, w| unsafe { w.shpg().bits(timing.shpg) });
+                    i2c.i2c_slpgr.modify(|_, w| unsafe { w.slpg().bits(timing.slpg) });
+                    if let Some((psc, tout)) = timeout {
+                        i2c.i2c_tout.write(|w| unsafe { w.psc().bits(psc).tout().bits(tout) });
+                        i2c.i2c_cr.modify(|_, w| w.entout().set_bit());
+                    }
                     // Enable the I2C port
                     i2c.i2c_cr.modify(|_, w| w.i2cen().set_bit());
-                    I2c { i2c }
+                    Ok(I2c {
+                        i2c,
+                        pins: (),
+                        scl: timing.scl,
+                        dma_result: Ok(()),
+                        multi_master: None,
+                        cycles_per_us: 0,
+                    })
+                }
+
+                /// Frees a bus that a slave holds low, e.g. because it was
+                /// reset in the middle of a transfer, and reinitialises the
+                /// peripheral. The pins are switched to GPIO for 9 SCL
+                /// pulses and a STOP, then handed back. A peripheral that
+                /// was created with the checked constructors keeps its pins
+                /// and is recovered with `recover` instead.
+                pub fn recover_bus<SCL, SDA>(&mut self, scl: SCL, sda: SDA, clocks: &Clocks) -> (SCL, SDA)
+                where
+                    SCL: PinScl<$I2CX> + BusPin,
+                    SDA: PinSda<$I2CX> + BusPin
+                {
+                    self.toggle_bus(scl, sda, clocks)
+                }
+            }
+
+            impl<SCL, SDA> I2c<$I2CX, (SCL, SDA)>
+            where
+                SCL: PinScl<$I2CX> + BusPin,
+                SDA: PinSda<$I2CX> + BusPin
+            {
+                /// Frees a bus that a slave holds low with the pins the
+                /// peripheral was created with, like `recover_bus`
+                pub fn recover(self, clocks: &Clocks) -> Self {
+                    let (mut i2c, (scl, sda)) = self.take_pins();
+                    let pins = i2c.toggle_bus(scl, sda, clocks);
+                    i2c.with_pins(pins)
+                }
+            }
+
+            impl<PINS> I2c<$I2CX, PINS> {
+                fn with_pins<P>(self, pins: P) -> I2c<$I2CX, P> {
+                    let I2c { i2c, scl, dma_result, multi_master, cycles_per_us, .. } = self;
+                    I2c { i2c, pins, scl, dma_result, multi_master, cycles_per_us }
+                }
+
+                fn take_pins(self) -> (I2c<$I2CX>, PINS) {
+                    let I2c { i2c, pins, scl, dma_result, multi_master, cycles_per_us } = self;
+                    (I2c { i2c, pins: (), scl, dma_result, multi_master, cycles_per_us }, pins)
+                }
+
+                /// The SCL frequency that was achieved
+                pub fn scl_frequency(&self) -> Hertz {
+                    self.scl
+                }
+
+                /// Shares the bus with other masters, blocking transfers wait
+                /// for the bus to become free and are repeated after losing
+                /// the arbitration. `None` switches back to single master
+                /// mode.
+                ///
+                /// `write_dma`, `read_dma` and `I2cTransaction` neither wait
+                /// nor retry, they can't block. An arbitration loss ends
+                /// them with `Error::Arbitration` and it is up to the caller
+                /// to start them again.
+                pub fn set_multi_master(&mut self, multi_master: Option<config::MultiMaster>, clocks: &Clocks) {
+                    self.multi_master = multi_master;
+                    self.cycles_per_us = clocks.hclk.0.div_ceil(1_000_000);
+                }
+
+                /// Runs `requests` from the I2C interrupt, see `I2cTransaction`
+                pub fn start_transaction<'r, 'b>(self, requests: &'r mut [Request<'b>]) -> I2cTransaction<'r, 'b, $I2CX, PINS> {
+                    I2cTransaction::<$I2CX, PINS>::new(self, requests)
+                }
+
+                /// Turns the bus into an SMBus, with the SMBus timeout
+                pub fn into_smbus(mut self, pec: bool, clocks: &Clocks) -> Result<smbus::SmBus<Self>, config::InvalidConfig> {
+                    self.set_timeout(smbus::TIMEOUT, clocks)?;
+                    Ok(smbus::SmBus::new(self, pec))
                 }
 
                 pub fn free(self) -> $I2CX {
                     self.i2c
                 }
 
-                pub fn listen(&mut self, event: Event) {
-                    match event {
-                        Event::RxBufferFull => self.i2c.i2c_ier.modify(|_, w| w.rxbfie().set_bit()),
-                        Event::DataRegisterEmtpyTransmitter => self.i2c.i2c_ier.modify(|_, w| w.txdeie().set_bit()),
-                        Event::DataRegisterEmptyReceiver => self.i2c.i2c_ier.modify(|_, w| w.rxdneie().set_bit()),
-                        Event::BusError => self.i2c.i2c_ier.modify(|_, w| w.buserrie().set_bit()),
-                        Event::ReceivedNotAcknowledge => self.i2c.i2c_ier.modify(|_, w| w.rxnackie().set_bit()),
-                        Event::ArbitrationLoss => self.i2c.i2c_ier.modify(|_, w| w.arblosie().set_bit()),
-                        Event::StopConditionDetected => self.i2c.i2c_ier.modify(|_, w| w.stoie().set_bit()),
-                        Event::StartConditionTransmit => self.i2c.i2c_ier.modify(|_, w| w.staie().set_bit())
+                /// Hands back the peripheral together with its pins
+                pub fn release(self) -> ($I2CX, PINS) {
+                    (self.i2c, self.pins)
+                }
+
+                /// Lets every blocking operation fail with `Error::Timeout`
+                /// if the bus doesn't make progress for the given time,
+                /// replacing the timeout of the config
+                pub fn set_timeout(&mut self, timeout: config::Timeout, clocks: &Clocks) -> Result<(), config::InvalidConfig> {
+                    match timeout.register(clocks.pclk)? {
+                        Some((psc, tout)) => {
+                            self.i2c.i2c_tout.write(|w| unsafe { w.psc().bits(psc).tout().bits(tout) });
+                            self.i2c.i2c_cr.modify(|_, w| w.entout().set_bit());
+                        }
+                        None => self.i2c.i2c_cr.modify(|_, w| w.entout().clear_bit()),
                     }
+                    Ok(())
                 }
 
-                pub fn unlisten(&mut self, event: Event) {
-                    match event {
-                        Event::RxBufferFull => self.i2c.i2c_ier.modify(|_, w| w.rxbfie().clear_bit()),
-                        Event::DataRegisterEmtpyTransmitter => self.i2c.i2c_ier.modify(|_, w| w.txdeie().clear_bit()),
-                        Event::DataRegisterEmptyReceiver => self.i2c.i2c_ier.modify(|_, w| w.rxdneie().clear_bit()),
-                        Event::BusError => self.i2c.i2c_ier.modify(|_, w| w.buserrie().clear_bit()),
-                        Event::ReceivedNotAcknowledge => self.i2c.i2c_ier.modify(|_, w| w.rxnackie().clear_bit()),
-                        Event::ArbitrationLoss => self.i2c.i2c_ier.modify(|_, w| w.arblosie().clear_bit()),
-                        Event::StopConditionDetected => self.i2c.i2c_ier.modify(|_, w| w.stoie().clear_bit()),
-                        Event::StartConditionTransmit => self.i2c.i2c_ier.modify(|_, w| w.staie().clear_bit())
+                /// Clocks the bus free with the pins as GPIOs and resets
+                /// the peripheral
+                fn toggle_bus<SCL, SDA>(&mut self, scl: SCL, sda: SDA, clocks: &Clocks) -> (SCL, SDA)
+                where
+                    SCL: PinScl<$I2CX> + BusPin,
+                    SDA: PinSda<$I2CX> + BusPin
+                {
+                    self.i2c.i2c_cr.modify(|_, w| w.i2cen().clear_bit());
+
+                    // bit bang at roughly 100 kHz
+                    let half_period = clocks.hclk.0 / 200_000;
+                    let mut scl = scl.into_gpio();
+                    let mut sda = sda.into_gpio();
+
+                    sda.set_high().ok();
+                    // A slave that is in the middle of sending a byte
+                    // releases SDA after at most 9 clocks
+                    for _ in 0..9 {
+                        scl.set_low().ok();
+                        cortex_m::asm::delay(half_period);
+                        scl.set_high().ok();
+                        cortex_m::asm::delay(half_period);
+                    }
+
+                    // STOP, SDA rises while SCL is high
+                    scl.set_low().ok();
+                    sda.set_low().ok();
+                    cortex_m::asm::delay(half_period);
+                    scl.set_high().ok();
+                    cortex_m::asm::delay(half_period);
+                    sda.set_high().ok();
+                    cortex_m::asm::delay(half_period);
+
+                    let scl = SCL::from_gpio(scl);
+                    let sda = SDA::from_gpio(sda);
+
+                    // Reset the peripheral, but keep the configuration
+                    let shpg = self.i2c.i2c_shpgr.read().shpg().bits();
+                    let slpg = self.i2c.i2c_slpgr.read().slpg().bits();
+                    let tout = self.i2c.i2c_tout.read().bits();
+                    let entout = self.i2c.i2c_cr.read().entout().bit();
+                    $I2CX::reset();
+                    self.i2c.i2c_shpgr.modify(|_, w| unsafe { w.shpg().bits(shpg) });
+                    self.i2c.i2c_slpgr.modify(|_, w| unsafe { w.slpg().bits(slpg) });
+                    self.i2c.i2c_tout.write(|w| unsafe { w.bits(tout) });
+                    self.i2c.i2c_cr.modify(|_, w| w.entout().bit(entout).i2cen().set_bit());
+
+                    (scl, sda)
+                }
+
+                /// Runs the operations as a single transaction, like the
+                /// `embedded-hal` 1.0 `I2c::transaction`, but accepts both
+                /// 7 and 10 bit addresses
+                pub fn transaction<A>(
+                    &mut self,
+                    address: A,
+                    operations: &mut [hal1::i2c::Operation<'_>],
+                ) -> Result<(), Error>
+                where
+                    A: Into<Address>
+                {
+                    let address = address.into();
+                    self.transfer(|i2c| {
+                        // Adjacent operations of the same direction are not
+                        // separated by a repeated START
+                        let mut previous_read = None;
+                        for i in 0..operations.len() {
+                            // The last byte before a direction change or the
+                            // STOP is NACKed
+                            let nack_last = !matches!(operations.get(i + 1), Some(hal1::i2c::Operation::Read(_)));
+                            match &mut operations[i] {
+                                hal1::i2c::Operation::Write(bytes) => {
+                                    if previous_read != Some(false) {
+                                        i2c.start(address, false)?;
+                                    }
+                                    i2c.write_bytes(bytes)?;
+                                    previous_read = Some(false);
+                                }
+                                hal1::i2c::Operation::Read(buffer) => {
+                                    if previous_read != Some(true) {
+                                        i2c.start_read(address, buffer.len(), nack_last)?;
+                                    }
+                                    i2c.read_bytes(buffer, nack_last)?;
+                                    previous_read = Some(true);
+                                }
+                            }
+                        }
+                        Ok(())
+                    })
+                }
+
+                /// Sends a START, or a repeated START if we already own the
+                /// bus, followed by the address frame
+                fn start(&mut self, addr: Address, read: bool) -> Result<(), Error> {
+                    self.request_start(addr, read);
+
+                    // wait for the start to be sent
+                    busy_wait!(self.i2c, sta, bit_is_set);
+                    // wait for the address frame to be sent and ACKed
+                    busy_wait!(self.i2c, adrs, bit_is_set);
+
+                    Ok(())
+                }
+
+                /// Lets the hardware send a START and the address frame
+                /// without waiting for it
+                fn request_start(&mut self, addr: Address, read: bool) {
+                    // Refer to User Manual page 454 and 455 for details
+                    // regarding this function, TAR holds the plain address,
+                    // the direction bit is added from RWD. In 10 bit mode
+                    // the header is sent by the hardware as well.
+                    let (ten_bit, tar) = match addr {
+                        Address::SevenBit(addr) => (false, (addr & 0x7f) as u16),
+                        Address::TenBit(addr) => (true, addr & 0x3ff),
+                    };
+                    self.i2c.i2c_cr.modify(|_, w| w.adrm().bit(ten_bit));
+                    self.i2c.i2c_tar.modify(|_, w| unsafe {
+                        w.rwd()
+                            // Direction
+                            .bit(read)
+                            // Set slave address
+                            .tar()
+                            .bits(tar)
+                    });
+                }
+
+                /// ACKing received bytes has to be configured before a
+                /// read is started, as a single byte has to be NACKed
+                /// right away
+                fn prepare_read(&mut self, len: usize, nack_last: bool) {
+                    self.i2c.i2c_cr.modify(|_, w| w.aa().bit(!(nack_last && len <= 1)));
+                }
+
+                /// Sends a START for a read
+                fn start_read(&mut self, addr: Address, len: usize, nack_last: bool) -> Result<(), Error> {
+                    self.prepare_read(len, nack_last);
+                    self.start(addr, true)
+                }
+
+                fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+                    for byte in bytes {
+                        // wait for the previous byte to be sent and acked
+                        busy_wait!(self.i2c, txde, bit_is_set);
+                        // send the byte
+                        self.i2c.i2c_dr.write(|w| unsafe { w.data().bits(*byte) });
+                    }
+                    // The last byte has to leave the data register before a
+                    // STOP or repeated START is requested
+                    busy_wait!(self.i2c, txde, bit_is_set);
+
+                    Ok(())
+                }
+
+                /// Receives into `buffer`, if `nack_last` is set the last
+                /// byte is NACKed to tell the slave that we are done
+                fn read_bytes(&mut self, buffer: &mut [u8], nack_last: bool) -> Result<(), Error> {
+                    let len = buffer.len();
+                    for (i, byte) in buffer.iter_mut().enumerate() {
+                        if nack_last && i + 1 == len {
+                            // Refer to User Manual page 456, AA decides
+                            // about the ACK of the byte that is currently
+                            // being received
+                            self.i2c.i2c_cr.modify(|_, w| w.aa().clear_bit());
+                        }
+                        // wait until we received data
+                        busy_wait!(self.i2c, rxdne, bit_is_set);
+
+                        *byte = self.i2c.i2c_dr.read().data().bits();
+                    }
+
+                    Ok(())
+                }
+
+                fn stop(&mut self) {
+                    self.i2c.i2c_cr.modify(|_, w| w.stop().set_bit());
+                }
+
+                /// Runs the phases of a transfer and ends it with a STOP,
+                /// also if one of them failed. In multi master mode the
+                /// transfer waits for the bus and is repeated after an
+                /// arbitration loss.
+                fn transfer(&mut self, mut f: impl FnMut(&mut Self) -> Result<(), Error>) -> Result<(), Error> {
+                    let mut retries = self.multi_master.map_or(0, |m| m.retries);
+                    loop {
+                        self.wait_bus_free()?;
+                        let result = f(self);
+                        match result {
+                            Ok(()) => self.stop(),
+                            Err(e) => self.abort(e),
+                        }
+
+                        match result {
+                            Err(Error::Arbitration) if retries > 0 => {
+                                retries -= 1;
+                                let delay = self.multi_master.map_or(0, |m| m.retry_delay);
+                                cortex_m::asm::delay(delay.saturating_mul(self.cycles_per_us));
+                            }
+                            _ => return result,
+                        }
+                    }
+                }
+
+                /// Waits until no other master uses the bus, only in multi
+                /// master mode
+                fn wait_bus_free(&mut self) -> Result<(), Error> {
+                    let timeout = match self.multi_master {
+                        Some(multi_master) => multi_master.bus_free_timeout,
+                        None => return Ok(()),
+                    };
+
+                    let mut waited = 0;
+                    while self.i2c.i2c_sr.read().busbusy().bit_is_set() {
+                        if waited >= timeout {
+                            return Err(Error::BusBusy);
+                        }
+                        cortex_m::asm::delay(self.cycles_per_us);
+                        waited += 1;
                     }
+                    Ok(())
+                }
+
+                /// Releases the bus after a failed transfer
+                fn abort(&mut self, error: Error) {
+                    if error != Error::Arbitration {
+                        // After an arbitration loss another master owns the
+                        // bus, we must not send a STOP
+                        self.stop();
+                    }
+                    // write 1 to clear
+                    self.i2c.i2c_sr.write(|w| {
+                        w.rxnack()
+                            .set_bit()
+                            .arblos()
+                            .set_bit()
+                            .buserr()
+                            .set_bit()
+                            .toutf()
+                            .set_bit()
+                    });
+                }
+
+                /// Reports the error flags without clearing them
+                fn error(&self) -> Result<(), Error> {
+                    let sr = self.i2c.i2c_sr.read();
+                    if sr.arblos().bit_is_set() {
+                        Err(Error::Arbitration)
+                    } else if sr.rxnack().bit_is_set() {
+                        Err(Error::NotAcknowledge)
+                    } else if sr.buserr().bit_is_set() {
+                        Err(Error::Bus)
+                    } else if sr.toutf().bit_is_set() {
+                        Err(Error::Timeout)
+                    } else {
+                        Ok(())
+                    }
+                }
+
+                /// Ends a DMA transfer with a STOP, or releases the bus after
+                /// an error. Does nothing if the transfer was ended already.
+                fn finish_dma(&mut self) {
+                    let cr = self.i2c.i2c_cr.read();
+                    if cr.txdmae().bit_is_clear() && cr.rxdmae().bit_is_clear() {
+                        return;
+                    }
+                    let write = cr.txdmae().bit_is_set();
+                    self.i2c.i2c_cr.modify(|_, w| w.txdmae().clear_bit().rxdmae().clear_bit().dmanack().clear_bit());
+
+                    // The last byte of a write has to leave the data
+                    // register before the STOP, a read is complete once the
+                    // PDMA fetched the NACKed last byte
+                    let result = self.error().and_then(|_| {
+                        if write {
+                            busy_wait!(self.i2c, txde, bit_is_set);
+                        }
+                        Ok(())
+                    });
+                    match result {
+                        // The inherent method, which sends the STOP
+                        Ok(()) => Self::stop(self),
+                        Err(e) => self.abort(e),
+                    }
+                    self.dma_result = result;
+                }
+
+                /// Writes `buffer` to the slave via the PDMA. The STOP is sent
+                /// by `Transfer::complete`, which should be called from the
+                /// transfer complete interrupt of the channel, otherwise the
+                /// bus is held until the transfer is waited for. Doesn't wait
+                /// for a busy bus in multi master mode, see `set_multi_master`.
+                pub fn write_dma<A, B, TX>(mut self, address: A, buffer: B, mut tx: TX) -> Transfer<B, TX, Self>
+                where
+                    A: Into<Address>,
+                    B: ReadBuffer<Word = u8>,
+                    TX: TxChannel<$I2CX>
+                {
+                    let (ptr, len) = unsafe { buffer.read_buffer() };
+                    assert!(len > 0 && len <= u16::MAX as usize);
+
+                    unsafe {
+                        tx.configure(
+                            ptr as u32,
+                            true,
+                            &self.i2c.i2c_dr as *const _ as u32,
+                            false,
+                            pdma::Width::Byte,
+                            len as u16,
+                        );
+                    }
+                    compiler_fence(Ordering::Release);
+                    tx.start();
+                    self.dma_result = Ok(());
+                    // Refer to User Manual page 458 for the DMA requests
+                    self.i2c.i2c_cr.modify(|_, w| w.txdmae().set_bit());
+                    self.request_start(address.into(), false);
+
+                    Transfer::new(buffer, tx, self)
+                }
+
+                /// Reads from the slave into `buffer` via the PDMA, the last
+                /// byte is NACKed by the hardware. The STOP is sent like for
+                /// `write_dma`.
+                pub fn read_dma<A, B, RX>(mut self, address: A, mut buffer: B, mut rx: RX) -> Transfer<B, RX, Self>
+                where
+                    A: Into<Address>,
+                    B: WriteBuffer<Word = u8>,
+                    RX: RxChannel<$I2CX>
+                {
+                    let (ptr, len) = unsafe { buffer.write_buffer() };
+                    assert!(len > 0 && len <= u16::MAX as usize);
+
+                    unsafe {
+                        rx.configure(
+                            &self.i2c.i2c_dr as *const _ as u32,
+                            false,
+                            ptr as u32,
+                            true,
+                            pdma::Width::Byte,
+                            len as u16,
+                        );
+                    }
+                    compiler_fence(Ordering::Release);
+                    rx.start();
+                    self.dma_result = Ok(());
+                    // DMANACK NACKs the byte that ends the PDMA transfer,
+                    // refer to User Manual page 458
+                    self.i2c.i2c_cr.modify(|_, w| w.rxdmae().set_bit().dmanack().set_bit());
+                    self.prepare_read(len, true);
+                    self.request_start(address.into(), true);
+
+                    Transfer::new(buffer, rx, self)
+                }
+
+                pub fn listen(&mut self, event: Event) {
+                    set_interrupt(&self.i2c, event, true);
+                }
+
+                pub fn unlisten(&mut self, event: Event) {
+                    set_interrupt(&self.i2c, event, false);
                 }
             }
 
             impl I2cExt<$I2CX> for $I2CX {
 	    		fn i2c<SCL, SDA, F>(
                     self,
-                    _scl: SCL,
-                    _sda: SDA,
+                    scl: SCL,
+                    sda: SDA,
                     freq: F,
                     clocks: &Clocks
-                ) -> I2c<$I2CX>
+                ) -> I2c<$I2CX, (SCL, SDA)>
                 where
                     SCL: PinScl<$I2CX>,
                     SDA: PinSda<$I2CX>,
                     F: Into<Hertz>
                 {
-                    I2c::$i2cX(self, freq, clocks)
+                    self.i2c_unchecked(freq, clocks).with_pins((scl, sda))
                 }
 
                 fn i2c_unchecked<F>(
@@ -192,194 +1176,477 @@ macro_rules! i2c {
                 where
                     F: Into<Hertz>
                 {
-                    I2c::$i2cX(self, freq, clocks)
+                    let config = config::Config::default().frequency(freq);
+                    I2c::$i2cX(self, config, clocks).unwrap()
+                }
+
+                fn i2c_with_config<SCL, SDA>(
+                    self,
+                    scl: SCL,
+                    sda: SDA,
+                    config: config::Config,
+                    clocks: &Clocks
+                ) -> Result<I2c<$I2CX, (SCL, SDA)>, config::InvalidConfig>
+                where
+                    SCL: PinScl<$I2CX>,
+                    SDA: PinSda<$I2CX>
+                {
+                    Ok(I2c::$i2cX(self, config, clocks)?.with_pins((scl, sda)))
+                }
+
+                fn i2c_with_config_unchecked(
+                    self,
+                    config: config::Config,
+                    clocks: &Clocks
+                ) -> Result<I2c<$I2CX>, config::InvalidConfig>
+                {
+                    I2c::$i2cX(self, config, clocks)
+                }
+
+                fn i2c_slave<SCL, SDA>(
+                    self,
+                    _scl: SCL,
+                    _sda: SDA,
+                    config: config::SlaveConfig
+                ) -> I2cSlave<$I2CX>
+                where
+                    SCL: PinScl<$I2CX>,
+                    SDA: PinSda<$I2CX>
+                {
+                    I2cSlave::$i2cX(self, config)
+                }
+
+                fn i2c_slave_unchecked(self, config: config::SlaveConfig) -> I2cSlave<$I2CX> {
+                    I2cSlave::$i2cX(self, config)
                 }
             }
 
-            impl Write for I2c<$I2CX> {
-                type Error = Error;
-                fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
-                    // Refer to User Manual page 454 for details regarding this
-                    // function
-                    self.i2c.i2c_tar.modify(|_, w| unsafe {
-                        w.rwd()
-                            // Direction, write to slave
-                            .clear_bit()
-                            // Set slave address
-                            .tar()
-                            .bits((addr << 1) as u16)
+            impl I2cSlave<$I2CX> {
+                fn $i2cX(i2c: $I2CX, config: config::SlaveConfig) -> Self {
+                    // reset the I2C port before using it
+                    $I2CX::reset();
+                    // enable the APB clock for the I2C port
+                    $I2CX::enable();
+
+                    // Refer to User Manual page 462 for the address matching
+                    i2c.i2c_addr.write(|w| unsafe { w.addr().bits(config.address as u16) });
+                    i2c.i2c_addmr.write(|w| unsafe { w.addmr().bits(config.mask as u16) });
+
+                    i2c.i2c_cr.modify(|_, w| {
+                        w.gcen()
+                            .bit(config.general_call)
+                            // ACK our address and all received bytes
+                            .aa()
+                            .set_bit()
+                            .i2cen()
+                            .set_bit()
                     });
+                    I2cSlave { i2c, nacked: false }
+                }
 
-                    // wait for the start to be sent
-                    busy_wait!(self.i2c, sta, bit_is_set);
-                    // wait for the address frame to be sent and ACKed
-                    busy_wait!(self.i2c, adrs, bit_is_set);
+                pub fn free(self) -> $I2CX {
+                    self.i2c
+                }
 
-                    for byte in bytes {
-                        // wait for the byte to be sent and acked
-                        busy_wait!(self.i2c, txde, bit_is_clear);
-                        // send the byte
-                        self.i2c.i2c_dr.write(|w| unsafe { w.data().bits(*byte) });
+                /// Returns the next thing that happened on the bus, it
+                /// has to be called until it returns `WouldBlock` in the
+                /// interrupt handler
+                pub fn next_event(&mut self) -> nb::Result<SlaveEvent, Error> {
+                    let sr = self.i2c.i2c_sr.read();
+
+                    if sr.buserr().bit_is_set() {
+                        // write 1 to clear
+                        self.i2c.i2c_sr.write(|w| w.buserr().set_bit());
+                        Err(nb::Error::Other(Error::Bus))
+                    } else if sr.adrs().bit_is_set() {
+                        // ADRS is cleared by reading I2C_SR
+                        self.nacked = false;
+                        Ok(SlaveEvent::AddressMatched {
+                            read: sr.txnrx().bit_is_set(),
+                            general_call: sr.gcs().bit_is_set(),
+                        })
+                    } else if sr.rxdne().bit_is_set() {
+                        Ok(SlaveEvent::DataReceived(self.i2c.i2c_dr.read().data().bits()))
+                    } else if sr.rxnack().bit_is_set() {
+                        // write 1 to clear
+                        self.i2c.i2c_sr.write(|w| w.rxnack().set_bit());
+                        self.nacked = true;
+                        Err(nb::Error::WouldBlock)
+                    } else if sr.sto().bit_is_set() {
+                        // STO is cleared by reading I2C_SR
+                        Ok(SlaveEvent::Stop)
+                    } else if sr.txde().bit_is_set() && sr.txnrx().bit_is_set() && !self.nacked {
+                        Ok(SlaveEvent::DataRequested)
+                    } else {
+                        Err(nb::Error::WouldBlock)
                     }
+                }
 
-                    // send the STOP
-                    self.i2c.i2c_cr.modify(|_, w| w.stop().set_bit());
+                /// Sends the byte after a `DataRequested`
+                pub fn respond(&mut self, byte: u8) {
+                    self.i2c.i2c_dr.write(|w| unsafe { w.data().bits(byte) });
+                }
 
-                    Ok(())
+                /// Handles all pending events, the byte `callback` returns
+                /// for a `DataRequested` is sent to the master, 0xFF if
+                /// there is none
+                pub fn on_interrupt<F>(&mut self, mut callback: F) -> Result<(), Error>
+                where
+                    F: FnMut(SlaveEvent) -> Option<u8>
+                {
+                    loop {
+                        match self.next_event() {
+                            Ok(event) => {
+                                let reply = callback(event);
+                                if event == SlaveEvent::DataRequested {
+                                    self.respond(reply.unwrap_or(0xff));
+                                }
+                            }
+                            Err(nb::Error::WouldBlock) => return Ok(()),
+                            Err(nb::Error::Other(e)) => return Err(e),
+                        }
+                    }
+                }
+
+                pub fn listen(&mut self, event: Event) {
+                    set_interrupt(&self.i2c, event, true);
+                }
+
+                pub fn unlisten(&mut self, event: Event) {
+                    set_interrupt(&self.i2c, event, false);
                 }
             }
 
-            impl Read for I2c<$I2CX> {
-                type Error = Error;
-                fn read(&mut self, addr: u8, buffer: &mut [u8],) -> Result<(), Error> {
-                    // Refer to User Manual page 455 for details regarding this
-                    // function
-                    self.i2c.i2c_tar.modify(|_, w| unsafe {
-                        w.rwd()
-                            // Direction, read from slave
-                            .set_bit()
-                            // Set slave address with read bit
-                            .tar()
-                            .bits(((addr << 1) | 1) as u16)
-                    });
+            impl<'r, 'b, PINS> I2cTransaction<'r, 'b, $I2CX, PINS> {
+                /// Starts the first request, the following ones are run
+                /// from `on_interrupt`. Requests that lost the arbitration
+                /// are not repeated, see `I2c::set_multi_master`.
+                pub fn new(mut i2c: I2c<$I2CX, PINS>, requests: &'r mut [Request<'b>]) -> Self {
+                    for event in TRANSACTION_EVENTS.iter() {
+                        i2c.listen(*event);
+                    }
+                    let mut transaction = I2cTransaction {
+                        i2c,
+                        requests,
+                        current: 0,
+                        state: State::Done,
+                    };
+                    transaction.start_request();
+                    transaction
+                }
 
-                    // wait for the start to be sent
-                    busy_wait!(self.i2c, sta, bit_is_set);
-                    // wait for the address frame to be sent and ACKed
-                    busy_wait!(self.i2c, adrs, bit_is_set);
+                /// Hands the peripheral back, a request that is still in
+                /// progress is aborted
+                pub fn free(mut self) -> I2c<$I2CX, PINS> {
+                    if !self.is_done() {
+                        self.i2c.stop();
+                        for event in TRANSACTION_EVENTS.iter() {
+                            self.i2c.unlisten(*event);
+                        }
+                    }
+                    self.i2c
+                }
 
-                    for byte in buffer {
-                        // wait until we received data
-                        busy_wait!(self.i2c, rxdne, bit_is_set);
+                /// Whether all requests are finished
+                pub fn is_done(&self) -> bool {
+                    self.current >= self.requests.len()
+                }
 
-                        *byte = self.i2c.i2c_dr.read().data().bits();
+                pub fn requests(&self) -> &[Request<'b>] {
+                    self.requests
+                }
+
+                /// Advances the transaction, it has to be called from the
+                /// I2C interrupt handler. `callback` gets the index and the
+                /// outcome of every request that finishes.
+                pub fn on_interrupt<F>(&mut self, mut callback: F)
+                where
+                    F: FnMut(usize, Result<(), Error>)
+                {
+                    if self.is_done() {
+                        return;
                     }
 
-                    // send the STOP
-                    self.i2c.i2c_cr.modify(|_, w| w.stop().set_bit());
+                    let sr = self.i2c.i2c.i2c_sr.read();
+                    let result = if sr.arblos().bit_is_set() {
+                        Some(Err(Error::Arbitration))
+                    } else if sr.rxnack().bit_is_set() {
+                        Some(Err(Error::NotAcknowledge))
+                    } else if sr.buserr().bit_is_set() {
+                        Some(Err(Error::Bus))
+                    } else if sr.toutf().bit_is_set() {
+                        // write 1 to clear
+                        self.i2c.i2c.i2c_sr.write(|w| w.toutf().set_bit());
+                        Some(Err(Error::Timeout))
+                    } else {
+                        self.advance(sr.txde().bit_is_set(), sr.rxdne().bit_is_set())
+                    };
 
-                    Ok(())
+                    if let Some(result) = result {
+                        if let Err(e) = result {
+                            self.i2c.abort(e);
+                        }
+                        self.requests[self.current].result = Some(result);
+                        callback(self.current, result);
+                        self.current += 1;
+                        self.start_request();
+                    }
+                }
+
+                fn start_request(&mut self) {
+                    let request = match self.requests.get(self.current) {
+                        Some(request) => request,
+                        None => {
+                            // TXDE would keep firing on the idle bus
+                            for event in TRANSACTION_EVENTS.iter() {
+                                self.i2c.unlisten(*event);
+                            }
+                            self.state = State::Done;
+                            return;
+                        }
+                    };
+
+                    let address = request.address;
+                    match &request.operation {
+                        Operation::Write(_) | Operation::WriteRead(_, _) => {
+                            self.i2c.request_start(address, false);
+                            self.state = State::Writing(0);
+                        }
+                        Operation::Read(buffer) => {
+                            let len = buffer.len();
+                            self.i2c.prepare_read(len, true);
+                            self.i2c.request_start(address, true);
+                            self.state = State::Reading(0);
+                        }
+                    }
+                }
+
+                /// Moves the next byte, returns the outcome once the
+                /// current request is finished
+                fn advance(&mut self, txde: bool, rxdne: bool) -> Option<Result<(), Error>> {
+                    let request = &mut self.requests[self.current];
+                    match self.state {
+                        State::Writing(written) if txde => {
+                            let bytes = match &request.operation {
+                                Operation::Write(bytes) | Operation::WriteRead(bytes, _) => *bytes,
+                                Operation::Read(_) => unreachable!(),
+                            };
+                            if let Some(byte) = bytes.get(written) {
+                                self.i2c.i2c.i2c_dr.write(|w| unsafe { w.data().bits(*byte) });
+                                self.state = State::Writing(written + 1);
+                                None
+                            } else if let Operation::WriteRead(_, buffer) = &request.operation {
+                                // The last byte left the data register, go on
+                                // with a repeated START
+                                let (address, len) = (request.address, buffer.len());
+                                self.i2c.prepare_read(len, true);
+                                self.i2c.request_start(address, true);
+                                self.state = State::Reading(0);
+                                None
+                            } else {
+                                self.i2c.stop();
+                                Some(Ok(()))
+                            }
+                        }
+                        State::Reading(received) if rxdne => {
+                            let byte = self.i2c.i2c.i2c_dr.read().data().bits();
+                            let buffer = match &mut request.operation {
+                                Operation::Read(buffer) | Operation::WriteRead(_, buffer) => buffer,
+                                Operation::Write(_) => unreachable!(),
+                            };
+                            if let Some(slot) = buffer.get_mut(received) {
+                                *slot = byte;
+                            }
+                            let received = received + 1;
+                            let len = buffer.len();
+                            if received + 1 == len {
+                                // NACK the last byte, refer to User Manual
+                                // page 456
+                                self.i2c.i2c.i2c_cr.modify(|_, w| w.aa().clear_bit());
+                            }
+                            if received >= len {
+                                self.i2c.stop();
+                                Some(Ok(()))
+                            } else {
+                                self.state = State::Reading(received);
+                                None
+                            }
+                        }
+                        _ => None,
+                    }
+                }
+            }
+
+            impl<PINS> TransferPayload for I2c<$I2CX, PINS> {
+                type Error = Error;
+
+                fn check(&mut self) -> Result<(), Error> {
+                    // The flags are cleared once the transfer is ended
+                    self.dma_result.and_then(|_| self.error())
+                }
+
+                fn complete(&mut self) {
+                    self.finish_dma();
+                }
+
+                fn stop(&mut self) {
+                    self.finish_dma();
+                }
+            }
+
+            impl<PINS> Write for I2c<$I2CX, PINS> {
+                type Error = Error;
+                fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<(), Error> {
+                    self.transfer(|i2c| {
+                        i2c.start(Address::SevenBit(addr), false)?;
+                        i2c.write_bytes(bytes)
+                    })
+                }
+            }
+
+            impl<PINS> Read for I2c<$I2CX, PINS> {
+                type Error = Error;
+                fn read(&mut self, addr: u8, buffer: &mut [u8],) -> Result<(), Error> {
+                    self.transfer(|i2c| {
+                        i2c.start_read(Address::SevenBit(addr), buffer.len(), true)?;
+                        i2c.read_bytes(buffer, true)
+                    })
                 }
             }
 
-            impl WriteRead for I2c<$I2CX> {
+            impl<PINS> WriteRead for I2c<$I2CX, PINS> {
                 type Error = Error;
-		fn write_read(
+                fn write_read(
                     &mut self,
                     addr: u8,
                     bytes: &[u8],
                     buffer: &mut [u8],
                 ) -> Result<(), Error> {
-                    // Refer to User Manual page 454 for details regarding this
-                    // part of the function
-                    self.i2c.i2c_tar.modify(|_, w| unsafe {
-                        w.rwd()
-                            // Direction, write to slave
-                            .clear_bit()
-                            // Set slave address
-                            .tar()
-                            .bits((addr << 1) as u16)
-                    });
+                    let addr = Address::SevenBit(addr);
+                    self.transfer(|i2c| {
+                        i2c.start(addr, false)?;
+                        i2c.write_bytes(bytes)?;
+                        // unlike write we explicitly don't send a stop here
+                        // as this function is only a single I2C transaction,
+                        // the START is sent as repeated START
+                        i2c.start_read(addr, buffer.len(), true)?;
+                        i2c.read_bytes(buffer, true)
+                    })
+                }
+            }
 
-                    // wait for the start to be sent
-                    busy_wait!(self.i2c, sta, bit_is_set);
-                    // wait for the address frame to be sent and ACKed
-                    busy_wait!(self.i2c, adrs, bit_is_set);
+            impl<PINS> hal1::i2c::I2c<hal1::i2c::SevenBitAddress> for I2c<$I2CX, PINS> {
+                fn transaction(
+                    &mut self,
+                    address: u8,
+                    operations: &mut [hal1::i2c::Operation<'_>],
+                ) -> Result<(), Error> {
+                    Self::transaction(self, Address::SevenBit(address), operations)
+                }
+            }
 
-                    for byte in bytes {
-                        // wait for the byte to be sent and acked
-                        busy_wait!(self.i2c, txde, bit_is_clear);
-                        // send the byte
-                        self.i2c.i2c_dr.write(|w| unsafe { w.data().bits(*byte) });
-                    }
+            impl<PINS> hal1::i2c::I2c<hal1::i2c::TenBitAddress> for I2c<$I2CX, PINS> {
+                fn transaction(
+                    &mut self,
+                    address: u16,
+                    operations: &mut [hal1::i2c::Operation<'_>],
+                ) -> Result<(), Error> {
+                    Self::transaction(self, Address::TenBit(address), operations)
+                }
+            }
+
+            impl<PINS> smbus::ReadBlock for I2c<$I2CX, PINS> {
+                fn read_block(
+                    &mut self,
+                    address: u8,
+                    data: &[u8],
+                    buffer: &mut [u8],
+                    extra: usize,
+                ) -> Result<Option<usize>, Error> {
+                    let address = Address::SevenBit(address);
+                    let mut count = None;
+                    self.transfer(|i2c| {
+                        if !data.is_empty() {
+                            i2c.start(address, false)?;
+                            i2c.write_bytes(data)?;
+                        }
+                        // The count byte is ACKed as the slave sends at
+                        // least one more byte after it, unless it's zero
+                        i2c.start_read(address, 2, true)?;
+                        i2c.read_bytes(&mut buffer[..1], false)?;
+                        let len = buffer[0] as usize + extra;
+                        count = (len < buffer.len()).then_some(buffer[0] as usize);
+                        if count.is_none() || len == 0 {
+                            // Ends the read with a NACKed byte that is
+                            // discarded
+                            return i2c.read_bytes(&mut [0], true);
+                        }
+                        i2c.read_bytes(&mut buffer[1..=len], true)
+                    })?;
+                    Ok(count)
+                }
+            }
+        )+
+    }
+}
 
-                    // unlike write we explicitly don't send a stop here as
-                    // this function is only a single I2C transaction
+// Expanded by the table in `instance`, the pins, their modes and the
+// alternate functions are resolved there
+macro_rules! pins {
+    ($I2CX:ty: SCL: [$($SCL:ident),*], SDA: [$($SDA:ident),*],) => {
+        $(
+            impl $crate::i2c::PinScl<$I2CX> for $SCL<Output<OpenDrain>, AF7> {}
 
-                    // Refer to User Manual page 455 for details regarding this
-                    // part function
-                    self.i2c.i2c_tar.modify(|_, w| unsafe {
-                        w.rwd()
-                            // Direction, read from slave
-                            .set_bit()
-                            // Set slave address with read bit
-                            .tar()
-                            .bits(((addr << 1) | 1) as u16)
-                    });
+            impl $crate::i2c::BusPin for $SCL<Output<OpenDrain>, AF7> {
+                // AF1 is GPIO
+                type Gpio = $SCL<Output<OpenDrain>, AF1>;
 
-                    // wait for the start to be sent
-                    busy_wait!(self.i2c, sta, bit_is_set);
-                    // wait for the address frame to be sent and ACKed
-                    busy_wait!(self.i2c, adrs, bit_is_set);
+                fn into_gpio(self) -> Self::Gpio {
+                    self.into_alternate_af1()
+                }
 
-                    for byte in buffer {
-                        // wait until we received data
-                        busy_wait!(self.i2c, rxdne, bit_is_set);
+                fn from_gpio(gpio: Self::Gpio) -> Self {
+                    gpio.into_alternate_af7()
+                }
+            }
+        )*
+        $(
+            impl $crate::i2c::PinSda<$I2CX> for $SDA<Output<OpenDrain>, AF7> {}
 
-                        *byte = self.i2c.i2c_dr.read().data().bits();
-                    }
+            impl $crate::i2c::BusPin for $SDA<Output<OpenDrain>, AF7> {
+                type Gpio = $SDA<Output<OpenDrain>, AF1>;
 
-                    // send the STOP
-                    self.i2c.i2c_cr.modify(|_, w| w.stop().set_bit());
+                fn into_gpio(self) -> Self::Gpio {
+                    self.into_alternate_af1()
+                }
 
-                    Ok(())
+                fn from_gpio(gpio: Self::Gpio) -> Self {
+                    gpio.into_alternate_af7()
                 }
             }
-        )+
+        )*
     }
 }
 
-macro_rules! pins {
-    ($($I2CX:ty: SCL: [$($SCL:ty),*] SDA: [$($SDA:ty),*])+) => {
+pub(crate) use pins;
+
+macro_rules! dma_channels {
+    ($($I2CX:ty: RX: $RX:ty, TX: $TX:ty)+) => {
         $(
-            $(
-                impl PinScl<$I2CX> for $SCL {}
-            )*
-            $(
-                impl PinSda<$I2CX> for $SDA {}
-            )*
+            impl RxChannel<$I2CX> for $RX {}
+            impl TxChannel<$I2CX> for $TX {}
         )+
     }
 }
 
 i2c! {
-    I2C0: (i2c0, i2c0en, i2c0rst),
-    I2C1: (i2c1, i2c0en, i2c1rst),
+    I2C0: i2c0,
+    I2C1: i2c1,
 }
 
-pins! {
-    I2C0:
-        SCL: [
-            PA4<Output<OpenDrain>, AF7>,
-            PC6<Output<OpenDrain>, AF7>,
-            PC12<Output<OpenDrain>, AF7>,
-            PB0<Output<OpenDrain>, AF7>,
-            PC14<Output<OpenDrain>, AF7>
-        ]
-
-        SDA: [
-            PA5<Output<OpenDrain>, AF7>,
-            PC7<Output<OpenDrain>, AF7>,
-            PD0<Output<OpenDrain>, AF7>,
-            PC13<Output<OpenDrain>, AF7>,
-            PB1<Output<OpenDrain>, AF7>,
-            PC15<Output<OpenDrain>, AF7>
-        ]
-
-    I2C1:
-        SCL: [
-            PA0<Output<OpenDrain>, AF7>,
-            PC4<Output<OpenDrain>, AF7>,
-            PB15<Output<OpenDrain>, AF7>,
-            PA14<Output<OpenDrain>, AF7>,
-            PB7<Output<OpenDrain>, AF7>
-        ]
-
-        SDA: [
-            PA1<Output<OpenDrain>, AF7>,
-            PC5<Output<OpenDrain>, AF7>,
-            PC0<Output<OpenDrain>, AF7>,
-            PA15<Output<OpenDrain>, AF7>,
-            PB8<Output<OpenDrain>, AF7>
-        ]
+// Refer to User Manual page 231 for the request mapping, several
+// peripherals share a channel, e.g. I2C1 and SPI1
+dma_channels! {
+    I2C0: RX: pdma::Ch4, TX: pdma::Ch5
+    I2C1: RX: pdma::Ch2, TX: pdma::Ch3
 }
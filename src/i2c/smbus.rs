@@ -0,0 +1,286 @@
+//! System Management Bus (SMBus) on top of an I2C bus
+//!
+//! The optional Packet Error Code (PEC) is a CRC-8 with the polynomial
+//! x^8 + x^2 + x + 1. It is computed in software as the CRC unit of the
+//! HT32 only offers 16 and 32 bit polynomials.
+use super::config::Timeout;
+use crate::hal1::i2c::{ErrorType, I2c, Operation};
+
+/// A device resets its interface once SCL was held low for this long, so
+/// the master gives up after it as well
+pub const TIMEOUT: Timeout = Timeout::Micros(25_000);
+
+/// Maximum number of data bytes in a block transfer
+pub const BLOCK_SIZE: usize = 32;
+
+/// The longest message, command, byte count, data and PEC
+const MAX_MESSAGE: usize = BLOCK_SIZE + 3;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The underlying I2C failed
+    I2c(E),
+    /// The received PEC doesn't match the message
+    Pec,
+    /// A block is longer than `BLOCK_SIZE` or than the buffer it should
+    /// be received into
+    BlockLength,
+}
+
+/// Updates `crc` with `data` according to the PEC rules
+pub fn crc8(mut crc: u8, data: &[u8]) -> u8 {
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Reads a message whose length is given by its first byte, which the
+/// `embedded-hal` transactions can't express
+pub trait ReadBlock: ErrorType {
+    /// Writes `data`, then reads the count byte into `buffer[0]` followed
+    /// by `count + extra` bytes, as a single transaction. Returns `None`
+    /// if they don't fit into `buffer`, the read is ended after the count
+    /// byte then.
+    fn read_block(
+        &mut self,
+        address: u8,
+        data: &[u8],
+        buffer: &mut [u8],
+        extra: usize,
+    ) -> Result<Option<usize>, Self::Error>;
+}
+
+#[derive(Debug)]
+pub struct SmBus<I2C> {
+    i2c: I2C,
+    pec: bool,
+}
+
+impl<I2C> SmBus<I2C>
+where
+    I2C: I2c,
+{
+    /// If `pec` is set every transfer, except for quick commands, carries
+    /// a PEC. Prefer `I2c::into_smbus` which also sets up the timeout.
+    pub fn new(i2c: I2C, pec: bool) -> Self {
+        SmBus { i2c, pec }
+    }
+
+    pub fn free(self) -> I2C {
+        self.i2c
+    }
+
+    /// Sends only the address, the direction bit is the data
+    pub fn quick_command(&mut self, address: u8, read: bool) -> Result<(), Error<I2C::Error>> {
+        if read {
+            self.i2c
+                .transaction(address, &mut [Operation::Read(&mut [])])
+                .map_err(Error::I2c)
+        } else {
+            self.raw(address, &[], &mut [])
+        }
+    }
+
+    pub fn send_byte(&mut self, address: u8, byte: u8) -> Result<(), Error<I2C::Error>> {
+        self.transfer(address, &[byte], &mut [])
+    }
+
+    pub fn receive_byte(&mut self, address: u8) -> Result<u8, Error<I2C::Error>> {
+        let mut byte = [0];
+        self.transfer(address, &[], &mut byte)?;
+        Ok(byte[0])
+    }
+
+    pub fn write_byte(&mut self, address: u8, command: u8, byte: u8) -> Result<(), Error<I2C::Error>> {
+        self.transfer(address, &[command, byte], &mut [])
+    }
+
+    pub fn read_byte(&mut self, address: u8, command: u8) -> Result<u8, Error<I2C::Error>> {
+        let mut byte = [0];
+        self.transfer(address, &[command], &mut byte)?;
+        Ok(byte[0])
+    }
+
+    /// Words are sent low byte first
+    pub fn write_word(&mut self, address: u8, command: u8, word: u16) -> Result<(), Error<I2C::Error>> {
+        let [low, high] = word.to_le_bytes();
+        self.transfer(address, &[command, low, high], &mut [])
+    }
+
+    pub fn read_word(&mut self, address: u8, command: u8) -> Result<u16, Error<I2C::Error>> {
+        let mut word = [0; 2];
+        self.transfer(address, &[command], &mut word)?;
+        Ok(u16::from_le_bytes(word))
+    }
+
+    /// Writes a word and reads the answer of the device in the same
+    /// transaction
+    pub fn process_call(&mut self, address: u8, command: u8, word: u16) -> Result<u16, Error<I2C::Error>> {
+        let [low, high] = word.to_le_bytes();
+        let mut answer = [0; 2];
+        self.transfer(address, &[command, low, high], &mut answer)?;
+        Ok(u16::from_le_bytes(answer))
+    }
+
+    /// Writes up to `BLOCK_SIZE` bytes, preceded by their count
+    pub fn block_write(&mut self, address: u8, command: u8, data: &[u8]) -> Result<(), Error<I2C::Error>> {
+        if data.len() > BLOCK_SIZE {
+            return Err(Error::BlockLength);
+        }
+        let mut message = [0; MAX_MESSAGE];
+        message[0] = command;
+        message[1] = data.len() as u8;
+        message[2..2 + data.len()].copy_from_slice(data);
+        self.transfer(address, &message[..2 + data.len()], &mut [])
+    }
+
+    /// Writes `data` and then reads into `buffer`, adding or checking the
+    /// PEC if enabled
+    fn transfer(&mut self, address: u8, data: &[u8], buffer: &mut [u8]) -> Result<(), Error<I2C::Error>> {
+        if !self.pec {
+            return self.raw(address, data, buffer);
+        }
+
+        // The PEC covers every byte on the bus, including the addresses
+        let crc = if data.is_empty() {
+            0
+        } else {
+            crc8(crc8(0, &[address << 1]), data)
+        };
+
+        if buffer.is_empty() {
+            let mut message = [0; MAX_MESSAGE];
+            message[..data.len()].copy_from_slice(data);
+            message[data.len()] = crc;
+            return self.raw(address, &message[..data.len() + 1], &mut []);
+        }
+
+        let mut message = [0; MAX_MESSAGE];
+        let message = &mut message[..buffer.len() + 1];
+        self.raw(address, data, message)?;
+        let (received, pec) = message.split_at(buffer.len());
+        if crc8(crc8(crc, &[(address << 1) | 1]), received) != pec[0] {
+            return Err(Error::Pec);
+        }
+        buffer.copy_from_slice(received);
+        Ok(())
+    }
+
+    /// Writes `data` and then reads into `buffer` as a single transaction
+    fn raw(&mut self, address: u8, data: &[u8], buffer: &mut [u8]) -> Result<(), Error<I2C::Error>> {
+        let result = if buffer.is_empty() {
+            self.i2c.transaction(address, &mut [Operation::Write(data)])
+        } else if data.is_empty() {
+            self.i2c.transaction(address, &mut [Operation::Read(buffer)])
+        } else {
+            self.i2c
+                .transaction(address, &mut [Operation::Write(data), Operation::Read(buffer)])
+        };
+        result.map_err(Error::I2c)
+    }
+}
+
+impl<I2C> SmBus<I2C>
+where
+    I2C: I2c + ReadBlock,
+{
+    /// Reads a block into `buffer` and returns its length. A block that
+    /// doesn't fit into `buffer` is an error.
+    pub fn block_read(&mut self, address: u8, command: u8, buffer: &mut [u8]) -> Result<usize, Error<I2C::Error>> {
+        let pec = self.pec as usize;
+        let mut message = [0; MAX_MESSAGE];
+        // count, data and PEC
+        let message = &mut message[..1 + buffer.len().min(BLOCK_SIZE) + pec];
+        let count = self
+            .i2c
+            .read_block(address, &[command], message, pec)
+            .map_err(Error::I2c)?
+            .ok_or(Error::BlockLength)?;
+
+        if self.pec {
+            let crc = crc8(0, &[address << 1, command, (address << 1) | 1]);
+            if crc8(crc, &message[..=count]) != message[count + 1] {
+                return Err(Error::Pec);
+            }
+        }
+        buffer[..count].copy_from_slice(&message[1..=count]);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::convert::Infallible;
+
+    /// A device that answers every block read with `block`
+    struct Device {
+        block: &'static [u8],
+    }
+
+    impl ErrorType for Device {
+        type Error = Infallible;
+    }
+
+    impl I2c for Device {
+        fn transaction(&mut self, _: u8, _: &mut [Operation<'_>]) -> Result<(), Infallible> {
+            unimplemented!()
+        }
+    }
+
+    impl ReadBlock for Device {
+        fn read_block(
+            &mut self,
+            _: u8,
+            _: &[u8],
+            buffer: &mut [u8],
+            extra: usize,
+        ) -> Result<Option<usize>, Infallible> {
+            let count = self.block[0] as usize;
+            if count + extra >= buffer.len() {
+                return Ok(None);
+            }
+            buffer[..=count + extra].copy_from_slice(&self.block[..=count + extra]);
+            Ok(Some(count))
+        }
+    }
+
+    #[test]
+    fn crc8_vectors() {
+        // The check value of CRC-8/SMBUS
+        assert_eq!(crc8(0, b"123456789"), 0xf4);
+        // A read word from an MLX90614 at 0x5a, command 0x07
+        assert_eq!(crc8(0, &[0xb4, 0x07, 0xb5, 0xd2, 0x3a]), 0x30);
+        // The PEC can be computed piecewise
+        assert_eq!(crc8(crc8(0, &[0xb4, 0x07]), &[0xb5, 0xd2, 0x3a]), 0x30);
+        // A message followed by its PEC gives zero
+        assert_eq!(crc8(0, &[0xb4, 0x07, 0xb5, 0xd2, 0x3a, 0x30]), 0);
+    }
+
+    #[test]
+    fn block_read() {
+        let mut buffer = [0; 4];
+        let device = Device {
+            block: &[0x02, 0xaa, 0xbb, 0x76],
+        };
+        let mut smbus = SmBus::new(device, true);
+        assert_eq!(smbus.block_read(0x5a, 0x07, &mut buffer).unwrap(), 2);
+        assert_eq!(buffer[..2], [0xaa, 0xbb]);
+
+        let device = Device {
+            block: &[0x02, 0xaa, 0xbb, 0x77],
+        };
+        let mut smbus = SmBus::new(device, true);
+        assert!(matches!(smbus.block_read(0x5a, 0x07, &mut buffer), Err(Error::Pec)));
+
+        let mut smbus = SmBus::new(smbus.free(), false);
+        assert!(matches!(
+            smbus.block_read(0x5a, 0x07, &mut buffer[..1]),
+            Err(Error::BlockLength)
+        ));
+    }
+}
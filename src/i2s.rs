@@ -0,0 +1,588 @@
+//! Inter-IC Sound (I2S) audio interface
+//!
+//! Samples are exchanged through the 8 entry TX and RX FIFOs, one `u32` per
+//! channel, right aligned to the configured data length. Either direction
+//! can be left out by passing `NoSdo` or `NoSdi` instead of the pin.
+use crate::ckcu::Clocks;
+use crate::ht32::I2S;
+use crate::instance::Instance;
+use crate::pdma::{self, ReadBuffer, Transfer, TransferPayload, WriteBuffer};
+use crate::time::Hertz;
+use core::marker::PhantomData;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Error {
+    /// The TX FIFO ran empty while a frame had to be sent
+    Underrun,
+    /// A sample was received while the RX FIFO was full
+    Overrun,
+    /// The PDMA could not access the buffer
+    Dma,
+}
+
+impl From<pdma::TransferError> for Error {
+    fn from(_: pdma::TransferError) -> Error {
+        Error::Dma
+    }
+}
+
+#[derive(Debug)]
+pub enum Event {
+    TxFifoThreshold,
+    RxFifoThreshold,
+    TxUnderrun,
+    RxOverrun,
+}
+
+pub trait PinMclk<I2S> {}
+pub trait PinBclk<I2S> {}
+pub trait PinWs<I2S> {}
+pub trait PinSdo<I2S> {
+    #[doc(hidden)]
+    const USED: bool = true;
+}
+pub trait PinSdi<I2S> {
+    #[doc(hidden)]
+    const USED: bool = true;
+}
+
+/// Stands in for the SDO pin in receive only operation
+#[derive(Debug)]
+pub struct NoSdo;
+
+/// Stands in for the SDI pin in transmit only operation
+#[derive(Debug)]
+pub struct NoSdi;
+
+impl<I2S> PinSdo<I2S> for NoSdo {
+    const USED: bool = false;
+}
+
+impl<I2S> PinSdi<I2S> for NoSdi {
+    const USED: bool = false;
+}
+
+/// In slave mode BCLK and WS are driven by the master
+pub trait PinBclkSlave<I2S> {}
+pub trait PinWsSlave<I2S> {}
+
+pub mod config {
+    use crate::time::{Hertz, U32Ext};
+
+    pub enum Format {
+        /// Standard I2S, the MSB follows WS with one BCLK delay
+        Philips,
+        LeftJustified,
+        RightJustified,
+    }
+
+    pub enum DataLength {
+        Bits16,
+        Bits24,
+        Bits32,
+    }
+
+    pub struct Config {
+        pub format: Format,
+        pub data_length: DataLength,
+        /// Only used in master mode, the closest achievable one is chosen
+        pub sample_rate: Hertz,
+    }
+
+    impl Config {
+        pub fn format(mut self, format: Format) -> Self {
+            self.format = format;
+            self
+        }
+
+        pub fn data_length(mut self, data_length: DataLength) -> Self {
+            self.data_length = data_length;
+            self
+        }
+
+        pub fn sample_rate<F>(mut self, sample_rate: F) -> Self
+        where
+            F: Into<Hertz>,
+        {
+            self.sample_rate = sample_rate.into();
+            self
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum InvalidConfig {
+        /// MCLK would have to be faster than PCLK
+        SampleRateTooHigh,
+        /// MCLK would have to be slower than the dividers allow
+        SampleRateTooLow,
+        /// The sample rate can't be hit within 1%
+        SampleRateUnsupported,
+    }
+
+    impl Default for Config {
+        fn default() -> Config {
+            Config {
+                format: Format::Philips,
+                data_length: DataLength::Bits16,
+                sample_rate: 48.khz().into(),
+            }
+        }
+    }
+}
+
+pub trait TxChannel<I2S>: pdma::Channel + pdma::Channels {}
+pub trait RxChannel<I2S>: pdma::Channel + pdma::Channels {}
+
+/// BCLK and WS are generated from PCLK (type state)
+#[derive(Debug)]
+pub struct Master;
+
+/// BCLK and WS are driven by another device (type state)
+#[derive(Debug)]
+pub struct Slave;
+
+#[derive(Debug)]
+pub struct I2s<I2S, MODE = Master> {
+    i2s: I2S,
+    _mode: PhantomData<MODE>,
+}
+
+pub trait I2sExt<I2S>: Sized {
+    /// Sets the I2S up as master, generating BCLK and WS
+    fn i2s<BCLK, WS, SDO, SDI>(
+        self,
+        bclk: BCLK,
+        ws: WS,
+        sdo: SDO,
+        sdi: SDI,
+        config: config::Config,
+        clocks: &Clocks,
+    ) -> Result<I2s<I2S, Master>, config::InvalidConfig>
+    where
+        BCLK: PinBclk<I2S>,
+        WS: PinWs<I2S>,
+        SDO: PinSdo<I2S>,
+        SDI: PinSdi<I2S>;
+
+    /// Enables both directions
+    fn i2s_unchecked(self, config: config::Config, clocks: &Clocks) -> Result<I2s<I2S, Master>, config::InvalidConfig>;
+
+    /// Sets the I2S up as slave, BCLK and WS are inputs
+    fn i2s_slave<BCLK, WS, SDO, SDI>(
+        self,
+        bclk: BCLK,
+        ws: WS,
+        sdo: SDO,
+        sdi: SDI,
+        config: config::Config,
+    ) -> I2s<I2S, Slave>
+    where
+        BCLK: PinBclkSlave<I2S>,
+        WS: PinWsSlave<I2S>,
+        SDO: PinSdo<I2S>,
+        SDI: PinSdi<I2S>;
+
+    /// Enables both directions
+    fn i2s_slave_unchecked(self, config: config::Config) -> I2s<I2S, Slave>;
+}
+
+/// Number of entries in both the TX and the RX FIFO
+const FIFO_DEPTH: u8 = 8;
+
+/// Slave mode bit in I2S_CR, refer to User Manual page 522
+const I2S_CR_SLAVE: u32 = 1 << 3;
+
+/// MCLK is generated as a multiple of the sample rate, which is what
+/// most codecs expect
+const MCLK_RATIO: u64 = 256;
+
+/// Number of BCLK cycles per channel for a data length, 24 bit data is
+/// sent in a 32 bit channel
+fn channel_bits(data_length: &config::DataLength) -> u32 {
+    match data_length {
+        config::DataLength::Bits16 => 16,
+        config::DataLength::Bits24 | config::DataLength::Bits32 => 32,
+    }
+}
+
+/// Values for X_DIV and Y_DIV in I2S_CDR for a sample rate
+///
+/// MCLK = PCLK * X_DIV / Y_DIV with X_DIV <= Y_DIV according to
+/// User Manual page 527, we look for the fraction that gets closest to
+/// MCLK_RATIO * fs
+fn mclk_div(sample_rate: Hertz, clocks: &Clocks) -> Result<(u8, u8), config::InvalidConfig> {
+    let pclk = clocks.pclk.0 as u64;
+    let mclk = sample_rate.0 as u64 * MCLK_RATIO;
+    if mclk > pclk {
+        return Err(config::InvalidConfig::SampleRateTooHigh);
+    }
+
+    let mut best: Option<(u64, u8, u8)> = None;
+    for y in 1..=255u64 {
+        let x = (mclk * y + pclk / 2) / pclk;
+        if x == 0 || x > y {
+            continue;
+        }
+        let achieved = pclk * x / y;
+        let error = achieved.abs_diff(mclk);
+        if best.is_none_or(|(best_error, _, _)| error < best_error) {
+            best = Some((error, x as u8, y as u8));
+        }
+    }
+
+    match best {
+        None => Err(config::InvalidConfig::SampleRateTooLow),
+        Some((error, _, _)) if error * 100 > mclk => Err(config::InvalidConfig::SampleRateUnsupported),
+        Some((_, x, y)) => Ok((x, y)),
+    }
+}
+
+macro_rules! i2s {
+    ($($I2SX:ident,)+) => {
+        $(
+            impl<MODE> I2s<$I2SX, MODE> {
+                fn new(
+                    i2s: $I2SX,
+                    config: config::Config,
+                    master: Option<&Clocks>,
+                    tx: bool,
+                    rx: bool,
+                ) -> Result<Self, config::InvalidConfig> {
+                    let div = match master {
+                        Some(clocks) => Some(mclk_div(config.sample_rate, clocks)?),
+                        None => None,
+                    };
+
+                    // reset the I2S before using it
+                    $I2SX::reset();
+                    // enable the APB clock for the I2S
+                    $I2SX::enable();
+
+                    let channel_bits = channel_bits(&config.data_length);
+                    if let Some((x, y)) = div {
+                        // BCLK = MCLK / (2 * N_DIV), with 2 channels per
+                        // sample -> N_DIV = MCLK_RATIO / (4 * channel_bits)
+                        let n = (MCLK_RATIO as u32 / (4 * channel_bits)) as u8;
+                        i2s.i2s_cdr.write(|w| unsafe { w.x_div().bits(x).y_div().bits(y).n_div().bits(n) });
+                        // Start the clock divider and wait until it runs
+                        i2s.i2s_cr.modify(|_, w| w.clkden().set_bit());
+                        while i2s.i2s_sr.read().clkrdy().bit_is_clear() {}
+                    }
+
+                    // Refer to User Manual page 522 for the encodings
+                    let format = match config.format {
+                        config::Format::Philips => 0,
+                        config::Format::LeftJustified => 1,
+                        config::Format::RightJustified => 2,
+                    };
+                    let sample_size = match config.data_length {
+                        config::DataLength::Bits16 => 1,
+                        config::DataLength::Bits24 => 2,
+                        config::DataLength::Bits32 => 3,
+                    };
+
+                    // The PAC lacks the master / slave selection in bit 3 of
+                    // I2S_CR, it is set for slave mode
+                    if master.is_none() {
+                        i2s.i2s_cr.modify(|r, w| unsafe { w.bits(r.bits() | I2S_CR_SLAVE) });
+                    }
+
+                    // 24 bit samples always occupy a 32 bit channel, 16 bit
+                    // ones are sent in 16 bit channels without extension
+                    i2s.i2s_cr.modify(|_, w| unsafe {
+                        w.format()
+                            .bits(format)
+                            .smpsize()
+                            .bits(sample_size)
+                            .bitext()
+                            .clear_bit()
+                            .txen()
+                            .bit(tx)
+                            .rxen()
+                            .bit(rx)
+                    });
+
+                    i2s.i2s_cr.modify(|_, w| w.i2sen().set_bit());
+                    Ok(I2s { i2s, _mode: PhantomData })
+                }
+
+                pub fn free(self) -> $I2SX {
+                    self.i2s
+                }
+
+                /// Queues one channel sample, left and right alternate
+                pub fn send(&mut self, sample: u32) -> nb::Result<(), Error> {
+                    let sr = self.i2s.i2s_sr.read();
+
+                    if sr.txfud().bit_is_set() {
+                        // write 1 to clear
+                        self.i2s.i2s_sr.write(|w| w.txfud().set_bit());
+                        Err(nb::Error::Other(Error::Underrun))
+                    } else if sr.txfs().bits() < FIFO_DEPTH {
+                        self.i2s.i2s_txdr.write(|w| unsafe { w.txdr().bits(sample) });
+                        Ok(())
+                    } else {
+                        Err(nb::Error::WouldBlock)
+                    }
+                }
+
+                /// Returns the next received channel sample
+                pub fn read(&mut self) -> nb::Result<u32, Error> {
+                    let sr = self.i2s.i2s_sr.read();
+
+                    if sr.rxfov().bit_is_set() {
+                        // write 1 to clear
+                        self.i2s.i2s_sr.write(|w| w.rxfov().set_bit());
+                        Err(nb::Error::Other(Error::Overrun))
+                    } else if sr.rxfs().bits() > 0 {
+                        Ok(self.i2s.i2s_rxdr.read().rxdr().bits())
+                    } else {
+                        Err(nb::Error::WouldBlock)
+                    }
+                }
+
+                /// Streams `samples` through the TX FIFO, blocking until all
+                /// of them are queued
+                pub fn write_all(&mut self, samples: &[u32]) -> Result<(), Error> {
+                    for sample in samples {
+                        nb::block!(self.send(*sample))?;
+                    }
+                    Ok(())
+                }
+
+                /// Fills `samples` from the RX FIFO
+                pub fn read_all(&mut self, samples: &mut [u32]) -> Result<(), Error> {
+                    for sample in samples {
+                        *sample = nb::block!(self.read())?;
+                    }
+                    Ok(())
+                }
+
+                /// Mutes the output while the TX FIFO keeps being drained
+                pub fn mute(&mut self, mute: bool) {
+                    self.i2s.i2s_cr.modify(|_, w| w.txmute().bit(mute));
+                }
+
+                pub fn listen(&mut self, event: Event) {
+                    match event {
+                        Event::TxFifoThreshold => self.i2s.i2s_ier.modify(|_, w| w.txftlien().set_bit()),
+                        Event::RxFifoThreshold => self.i2s.i2s_ier.modify(|_, w| w.rxftlien().set_bit()),
+                        Event::TxUnderrun => self.i2s.i2s_ier.modify(|_, w| w.txudien().set_bit()),
+                        Event::RxOverrun => self.i2s.i2s_ier.modify(|_, w| w.rxovien().set_bit()),
+                    }
+                }
+
+                pub fn unlisten(&mut self, event: Event) {
+                    match event {
+                        Event::TxFifoThreshold => self.i2s.i2s_ier.modify(|_, w| w.txftlien().clear_bit()),
+                        Event::RxFifoThreshold => self.i2s.i2s_ier.modify(|_, w| w.rxftlien().clear_bit()),
+                        Event::TxUnderrun => self.i2s.i2s_ier.modify(|_, w| w.txudien().clear_bit()),
+                        Event::RxOverrun => self.i2s.i2s_ier.modify(|_, w| w.rxovien().clear_bit()),
+                    }
+                }
+            }
+
+            impl I2s<$I2SX, Master> {
+                /// Outputs MCLK for the codec
+                pub fn with_mclk<MCLK>(self, _mclk: MCLK) -> Self
+                where
+                    MCLK: PinMclk<$I2SX>
+                {
+                    self.i2s.i2s_cr.modify(|_, w| w.mclken().set_bit());
+                    self
+                }
+
+                /// The sample rate that was actually achieved
+                pub fn sample_rate(&self, clocks: &Clocks) -> Hertz {
+                    let cdr = self.i2s.i2s_cdr.read();
+                    let x = cdr.x_div().bits() as u64;
+                    let y = cdr.y_div().bits() as u64;
+                    Hertz((clocks.pclk.0 as u64 * x / (y * MCLK_RATIO)) as u32)
+                }
+            }
+
+            impl<MODE> I2s<$I2SX, MODE> {
+                /// Streams `buffer` into the TX FIFO via the PDMA, one `u32`
+                /// per channel sample like `send`
+                pub fn write_dma<B, TX>(self, buffer: B, mut tx: TX) -> Transfer<B, TX, Self>
+                where
+                    B: ReadBuffer<Word = u32>,
+                    TX: TxChannel<$I2SX>
+                {
+                    let (ptr, len) = unsafe { buffer.read_buffer() };
+                    assert!(len <= u16::MAX as usize);
+
+                    unsafe {
+                        tx.configure(
+                            ptr as u32,
+                            true,
+                            &self.i2s.i2s_txdr as *const _ as u32,
+                            false,
+                            pdma::Width::Word,
+                            len as u16,
+                        );
+                    }
+                    compiler_fence(Ordering::Release);
+                    tx.start();
+                    self.i2s.i2s_cr.modify(|_, w| w.txdmaen().set_bit());
+
+                    Transfer::new(buffer, tx, self)
+                }
+
+                /// Fills `buffer` from the RX FIFO via the PDMA
+                pub fn read_dma<B, RX>(self, mut buffer: B, mut rx: RX) -> Transfer<B, RX, Self>
+                where
+                    B: WriteBuffer<Word = u32>,
+                    RX: RxChannel<$I2SX>
+                {
+                    let (ptr, len) = unsafe { buffer.write_buffer() };
+                    assert!(len <= u16::MAX as usize);
+
+                    unsafe {
+                        rx.configure(
+                            &self.i2s.i2s_rxdr as *const _ as u32,
+                            false,
+                            ptr as u32,
+                            true,
+                            pdma::Width::Word,
+                            len as u16,
+                        );
+                    }
+                    compiler_fence(Ordering::Release);
+                    rx.start();
+                    self.i2s.i2s_cr.modify(|_, w| w.rxdmaen().set_bit());
+
+                    Transfer::new(buffer, rx, self)
+                }
+            }
+
+            impl<MODE> TransferPayload for I2s<$I2SX, MODE> {
+                type Error = Error;
+
+                fn check(&mut self) -> Result<(), Error> {
+                    let cr = self.i2s.i2s_cr.read();
+                    let sr = self.i2s.i2s_sr.read();
+                    if cr.txdmaen().bit_is_set() && sr.txfud().bit_is_set() {
+                        // write 1 to clear
+                        self.i2s.i2s_sr.write(|w| w.txfud().set_bit());
+                        Err(Error::Underrun)
+                    } else if cr.rxdmaen().bit_is_set() && sr.rxfov().bit_is_set() {
+                        // write 1 to clear
+                        self.i2s.i2s_sr.write(|w| w.rxfov().set_bit());
+                        Err(Error::Overrun)
+                    } else {
+                        Ok(())
+                    }
+                }
+
+                fn stop(&mut self) {
+                    self.i2s.i2s_cr.modify(|_, w| w.txdmaen().clear_bit().rxdmaen().clear_bit());
+                }
+            }
+
+            impl I2sExt<$I2SX> for $I2SX {
+                fn i2s<BCLK, WS, SDO, SDI>(
+                    self,
+                    _bclk: BCLK,
+                    _ws: WS,
+                    _sdo: SDO,
+                    _sdi: SDI,
+                    config: config::Config,
+                    clocks: &Clocks,
+                ) -> Result<I2s<$I2SX, Master>, config::InvalidConfig>
+                where
+                    BCLK: PinBclk<$I2SX>,
+                    WS: PinWs<$I2SX>,
+                    SDO: PinSdo<$I2SX>,
+                    SDI: PinSdi<$I2SX>
+                {
+                    I2s::<$I2SX, Master>::new(self, config, Some(clocks), SDO::USED, SDI::USED)
+                }
+
+                fn i2s_unchecked(
+                    self,
+                    config: config::Config,
+                    clocks: &Clocks
+                ) -> Result<I2s<$I2SX, Master>, config::InvalidConfig> {
+                    I2s::<$I2SX, Master>::new(self, config, Some(clocks), true, true)
+                }
+
+                fn i2s_slave<BCLK, WS, SDO, SDI>(
+                    self,
+                    _bclk: BCLK,
+                    _ws: WS,
+                    _sdo: SDO,
+                    _sdi: SDI,
+                    config: config::Config
+                ) -> I2s<$I2SX, Slave>
+                where
+                    BCLK: PinBclkSlave<$I2SX>,
+                    WS: PinWsSlave<$I2SX>,
+                    SDO: PinSdo<$I2SX>,
+                    SDI: PinSdi<$I2SX>
+                {
+                    // Without clocks to compute there is nothing to fail
+                    I2s::<$I2SX, Slave>::new(self, config, None, SDO::USED, SDI::USED).unwrap()
+                }
+
+                fn i2s_slave_unchecked(self, config: config::Config) -> I2s<$I2SX, Slave> {
+                    I2s::<$I2SX, Slave>::new(self, config, None, true, true).unwrap()
+                }
+            }
+        )+
+    }
+}
+
+// Expanded by the table in `instance`, the pins, their modes and the
+// alternate functions are resolved there
+macro_rules! pins {
+    (
+        $I2SX:ty:
+        MCLK: [$($MCLK:ident),*],
+        BCLK: [$($BCLK:ident),*],
+        WS: [$($WS:ident),*],
+        SDO: [$($SDO:ident),*],
+        SDI: [$($SDI:ident),*],
+    ) => {
+        $(
+            impl $crate::i2s::PinMclk<$I2SX> for $MCLK<Output<PushPull>, AF10> {}
+        )*
+        $(
+            impl $crate::i2s::PinBclk<$I2SX> for $BCLK<Output<PushPull>, AF10> {}
+            impl $crate::i2s::PinBclkSlave<$I2SX> for $BCLK<Input<Floating>, AF10> {}
+        )*
+        $(
+            impl $crate::i2s::PinWs<$I2SX> for $WS<Output<PushPull>, AF10> {}
+            impl $crate::i2s::PinWsSlave<$I2SX> for $WS<Input<Floating>, AF10> {}
+        )*
+        $(
+            impl $crate::i2s::PinSdo<$I2SX> for $SDO<Output<PushPull>, AF10> {}
+        )*
+        $(
+            impl $crate::i2s::PinSdi<$I2SX> for $SDI<Input<Floating>, AF10> {}
+        )*
+    }
+}
+
+pub(crate) use pins;
+
+i2s! {
+    I2S,
+}
+
+macro_rules! dma_channels {
+    ($($I2SX:ty: RX: $RX:ty, TX: $TX:ty)+) => {
+        $(
+            impl RxChannel<$I2SX> for $RX {}
+            impl TxChannel<$I2SX> for $TX {}
+        )+
+    }
+}
+
+// Refer to User Manual page 231 for the request mapping, the channels are
+// shared with I2C0
+dma_channels! {
+    I2S: RX: pdma::Ch4, TX: pdma::Ch5
+}
@@ -0,0 +1,270 @@
+//! Per instance bindings of the peripherals
+//!
+//! The bits of every instance in the clock and reset controllers, its
+//! interrupt and its pins are listed in a single table that is shared by
+//! the drivers. The pins are grouped by their function, the driver named
+//! in the table implements its pin traits for them with the mode and
+//! alternate function that the function needs.
+use crate::gpio::{
+    gpioa::{PA0, PA1, PA10, PA11, PA14, PA15, PA2, PA3, PA4, PA5, PA6, PA7, PA8, PA9},
+    gpiob::{PB0, PB1, PB15, PB2, PB3, PB4, PB5, PB6, PB7, PB8},
+    gpioc::{PC0, PC1, PC10, PC11, PC12, PC13, PC14, PC15, PC2, PC3, PC4, PC5, PC6, PC7, PC8, PC9},
+    gpiod::PD0,
+    Floating, Input, OpenDrain, Output, PushPull, AF1, AF10, AF5, AF6, AF7, AF8,
+};
+use crate::ht32::{
+    ckcu::CKCU_APBCCR0, rstcu::RSTCU_APBPRSTR0, Interrupt, CKCU, I2C0, I2C1, I2S, RSTCU, SCI, SPI0, SPI1, UART0, UART1,
+    USART0, USART1,
+};
+
+mod sealed {
+    use super::{CKCU_APBCCR0, RSTCU_APBPRSTR0};
+
+    pub trait Sealed {
+        /// Sets the enable bit of the instance in `apbccr0`
+        fn set_enable(apbccr0: &CKCU_APBCCR0);
+
+        /// Sets the reset bit of the instance in `apbprstr0`
+        fn set_reset(apbprstr0: &RSTCU_APBPRSTR0);
+    }
+}
+
+/// A peripheral instance that is clocked from the APB
+pub trait Instance: sealed::Sealed {
+    /// The interrupt of the instance, e.g. to unmask it in the NVIC
+    const INTERRUPT: Interrupt;
+
+    /// Enables the APB clock of the instance
+    #[doc(hidden)]
+    fn enable() {
+        let ckcu = unsafe { &*CKCU::ptr() };
+        Self::set_enable(&ckcu.ckcu_apbccr0);
+    }
+
+    /// Resets all registers of the instance
+    #[doc(hidden)]
+    fn reset() {
+        let rstcu = unsafe { &*RSTCU::ptr() };
+        Self::set_reset(&rstcu.rstcu_apbprstr0);
+    }
+}
+
+/// Whether the enable and reset bit belong to the instance `per`, e.g.
+/// `spi0en` and `spi0rst` to `SPI0`. Both bits have to share a stem, which
+/// starts with the first letter of the instance and ends with its index.
+/// This rejects copy pasted and swapped table entries at compile time.
+const fn matches(per: &str, en: &str, rst: &str) -> bool {
+    let (per, en, rst) = (per.as_bytes(), en.as_bytes(), rst.as_bytes());
+    if en.len() < 3 || rst.len() < 4 || !ends_with(en, b"en") || !ends_with(rst, b"rst") {
+        return false;
+    }
+    let stem = en.len() - 2;
+    if stem != rst.len() - 3 || !equal(en, rst, stem) {
+        return false;
+    }
+    if en[0] != per[0].to_ascii_lowercase() {
+        return false;
+    }
+    // Only the instances that exist more than once carry an index
+    let index = per[per.len() - 1];
+    !index.is_ascii_digit() || en[stem - 1] == index
+}
+
+const fn ends_with(name: &[u8], suffix: &[u8]) -> bool {
+    let mut i = 0;
+    while i < suffix.len() {
+        if name[name.len() - suffix.len() + i] != suffix[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Whether the first `len` bytes of `a` and `b` are equal
+const fn equal(a: &[u8], b: &[u8], len: usize) -> bool {
+    let mut i = 0;
+    while i < len {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+macro_rules! instances {
+    ($(
+        $PER:ident: ($en:ident, $rst:ident) => $driver:ident {
+            $($FUNCTION:ident: [$($PIN:ident),*],)+
+        },
+    )+) => {
+        $(
+            impl sealed::Sealed for $PER {
+                fn set_enable(apbccr0: &CKCU_APBCCR0) {
+                    apbccr0.modify(|_, w| w.$en().set_bit());
+                }
+
+                fn set_reset(apbprstr0: &RSTCU_APBPRSTR0) {
+                    apbprstr0.modify(|_, w| w.$rst().set_bit());
+                }
+            }
+
+            impl Instance for $PER {
+                const INTERRUPT: Interrupt = Interrupt::$PER;
+            }
+
+            $crate::$driver::pins! {
+                $PER: $($FUNCTION: [$($PIN),*],)+
+            }
+        )+
+
+        // The length of the array is only 0 if the bits belong to the
+        // instance
+        $(
+            const _: [(); 0] = [(); !matches(stringify!($PER), stringify!($en), stringify!($rst)) as usize];
+        )+
+
+        #[cfg(test)]
+        const TABLE: &[(&str, &str, &str)] = &[$((stringify!($PER), stringify!($en), stringify!($rst))),+];
+
+        /// The driver, instance, function and name of every pin
+        #[cfg(test)]
+        const PINS: &[(&str, &str, &str, &str)] = &[
+            $($($((stringify!($driver), stringify!($PER), stringify!($FUNCTION), stringify!($PIN)),)*)+)+
+        ];
+    }
+}
+
+// Refer to User Manual page 98 and 124 for the bits, and to the pin
+// assignment of the datasheet for the pins
+instances! {
+    I2C0: (i2c0en, i2c0rst) => i2c {
+        SCL: [PA4, PC6, PC12, PB0, PC14],
+        SDA: [PA5, PC7, PD0, PC13, PB1, PC15],
+    },
+    I2C1: (i2c1en, i2c1rst) => i2c {
+        SCL: [PA0, PC4, PB15, PA14, PB7],
+        SDA: [PA1, PC5, PC0, PA15, PB8],
+    },
+    SPI0: (spi0en, spi0rst) => spi {
+        SCK: [PA4, PC0, PB3],
+        MISO: [PA6, PA11, PB5],
+        MOSI: [PA5, PA9, PB4],
+        SEL: [PA7, PB2],
+    },
+    SPI1: (spi1en, spi1rst) => spi {
+        SCK: [PA0, PC5, PC11, PA15, PC2],
+        MISO: [PA2, PC9, PC13, PB1, PB6],
+        MOSI: [PA1, PC8, PC12, PB0, PC3],
+        SEL: [PA3, PC10, PA14, PC4],
+    },
+    UART0: (ur0en, ur0rst) => serial {
+        TX: [PC4, PB2, PB6],
+        RX: [PC5, PB3, PB8],
+    },
+    UART1: (ur1en, ur1rst) => serial {
+        TX: [PC12, PB4, PC1],
+        RX: [PC13, PB5, PC3],
+    },
+    USART0: (usr0en, usr0rst) => serial {
+        TX: [PA2, PC6, PA8, PB0],
+        RX: [PA3, PC7, PA10, PB1],
+        SCK: [PA1],
+    },
+    USART1: (usr1en, usr1rst) => serial {
+        TX: [PA4, PB15, PA14],
+        RX: [PA5, PC0, PA15],
+        SCK: [PA7],
+    },
+    SCI: (sci0en, sci0rst) => sci {
+        CLK: [PA6, PC11],
+        DIO: [PA7, PC12],
+    },
+    I2S: (i2sen, i2srst) => i2s {
+        MCLK: [PB2, PC3],
+        BCLK: [PB3, PC5],
+        WS: [PB4, PC4],
+        SDO: [PB5, PC8],
+        SDI: [PB6, PC9],
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::UnsafeCell;
+
+    /// The bits that `set` sets in a register, which is emulated in memory
+    fn bits<REG>(set: fn(&REG)) -> u32 {
+        let memory = UnsafeCell::new(0u32);
+        set(unsafe { &*(memory.get() as *const REG) });
+        memory.into_inner()
+    }
+
+    /// Checks the bindings of `PER` against the User Manual, the interrupt
+    /// number and the position of the enable and reset bit
+    fn check<PER: Instance>(interrupt: u8, bit: u32) {
+        assert_eq!(PER::INTERRUPT as u8, interrupt);
+        assert_eq!(bits(PER::set_enable), 1 << bit);
+        assert_eq!(bits(PER::set_reset), 1 << bit);
+    }
+
+    #[test]
+    fn bindings() {
+        check::<I2C0>(19, 0);
+        check::<I2C1>(20, 1);
+        check::<SPI0>(21, 4);
+        check::<SPI1>(22, 5);
+        check::<USART0>(23, 8);
+        check::<USART1>(24, 9);
+        check::<UART0>(25, 10);
+        check::<UART1>(26, 11);
+        check::<SCI>(27, 24);
+        check::<I2S>(28, 25);
+    }
+
+    #[test]
+    fn pins() {
+        // A pin has a single function per alternate function, which is
+        // the same for all instances of a driver
+        for (i, (driver, per, function, pin)) in PINS.iter().enumerate() {
+            for (other_driver, other_per, other_function, other_pin) in &PINS[i + 1..] {
+                assert!(
+                    driver != other_driver || pin != other_pin,
+                    "{}: {} {} and {} {}",
+                    pin,
+                    per,
+                    function,
+                    other_per,
+                    other_function
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn table() {
+        for (per, en, rst) in TABLE {
+            assert!(matches(per, en, rst), "{}: ({}, {})", per, en, rst);
+        }
+    }
+
+    #[test]
+    fn swapped_index() {
+        assert!(!matches("SPI0", "spi1en", "spi1rst"));
+        assert!(!matches("USART1", "usr1en", "usr0rst"));
+    }
+
+    #[test]
+    fn other_peripheral() {
+        assert!(!matches("I2C0", "spi0en", "spi0rst"));
+        assert!(!matches("UART0", "ur0en", "usr0rst"));
+    }
+
+    #[test]
+    fn wrong_register() {
+        assert!(!matches("SPI0", "spi0rst", "spi0en"));
+        assert!(!matches("SPI0", "en", "rst"));
+    }
+}
@@ -8,11 +8,13 @@ compile_error!(
 );
 
 pub use embedded_hal as hal;
+/// embedded-hal 1.0, implemented alongside the 0.2 traits
+pub use embedded_hal_1 as hal1;
 
 pub use nb;
 pub use nb::block;
 
-#[cfg(any(feature = "ht32f52342_52"))]
+#[cfg(feature = "ht32f52342_52")]
 pub use ht32f5xxxx::ht32f52342_52 as ht32;
 
 // Enable use of interrupt macro
@@ -34,11 +36,23 @@ pub mod ckcu;
 #[cfg(feature = "device-selected")]
 pub mod gpio;
 
+#[cfg(feature = "device-selected")]
+pub mod instance;
+
+#[cfg(feature = "device-selected")]
+pub mod pdma;
+
 #[cfg(feature = "device-selected")]
 pub mod spi;
 
 #[cfg(feature = "device-selected")]
 pub mod i2c;
 
+#[cfg(feature = "device-selected")]
+pub mod i2s;
+
 #[cfg(feature = "device-selected")]
 pub mod serial;
+
+#[cfg(feature = "device-selected")]
+pub mod sci;
@@ -0,0 +1,382 @@
+//! Peripheral Direct Memory Access (PDMA) controller
+//!
+//! The request lines of the peripherals are hard wired to the channels,
+//! the drivers only accept the channel that belongs to them. A channel
+//! serves several peripherals, owning it decides which one uses it. Buffers are
+//! handed over to a `Transfer` and only given back once the PDMA is done
+//! with them, which is why they have to be `'static`.
+use crate::ht32::{CKCU, PDMA};
+use core::sync::atomic::{compiler_fence, Ordering};
+pub use embedded_dma::{ReadBuffer, WriteBuffer};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Event {
+    TransferComplete,
+    HalfTransfer,
+    TransferError,
+}
+
+/// The PDMA could not access the source or the destination
+#[derive(Debug)]
+pub struct TransferError;
+
+/// Size of one data unit that is moved per request
+#[derive(Debug, Clone, Copy)]
+pub enum Width {
+    Byte,
+    HalfWord,
+    Word,
+}
+
+/// Types that can be moved by the PDMA
+pub trait Word {
+    const WIDTH: Width;
+}
+
+impl Word for u8 {
+    const WIDTH: Width = Width::Byte;
+}
+
+impl Word for u16 {
+    const WIDTH: Width = Width::HalfWord;
+}
+
+impl Word for u32 {
+    const WIDTH: Width = Width::Word;
+}
+
+/// A single PDMA channel
+pub trait Channel {
+    /// Sets up a transfer of `len` data units from `src` to `dst`, each
+    /// address is incremented after every unit if requested.
+    ///
+    /// # Safety
+    /// Both addresses have to stay valid until the channel is stopped.
+    unsafe fn configure(&mut self, src: u32, src_inc: bool, dst: u32, dst_inc: bool, width: Width, len: u16);
+
+    /// Enables the channel, it starts moving data on the next request of
+    /// its peripheral
+    fn start(&mut self);
+
+    /// Disables the channel and clears its flags
+    fn stop(&mut self);
+
+    fn is_complete(&self) -> bool;
+
+    fn has_error(&self) -> bool;
+
+    fn listen(&mut self, event: Event);
+
+    fn unlisten(&mut self, event: Event);
+}
+
+/// The channels a `Transfer` runs on, either a single one or a pair for
+/// transfers that move data in both directions
+pub trait Channels {
+    fn stop(&mut self);
+
+    fn is_complete(&self) -> bool;
+
+    fn has_error(&self) -> bool;
+
+    fn unlisten(&mut self, event: Event);
+}
+
+impl<A, B> Channels for (A, B)
+where
+    A: Channel,
+    B: Channel,
+{
+    fn stop(&mut self) {
+        self.0.stop();
+        self.1.stop();
+    }
+
+    fn is_complete(&self) -> bool {
+        self.0.is_complete() && self.1.is_complete()
+    }
+
+    fn has_error(&self) -> bool {
+        self.0.has_error() || self.1.has_error()
+    }
+
+    fn unlisten(&mut self, event: Event) {
+        self.0.unlisten(event);
+        self.1.unlisten(event);
+    }
+}
+
+/// A peripheral that can take part in a `Transfer`
+pub trait TransferPayload {
+    type Error: From<TransferError>;
+
+    /// Reports errors of the peripheral that end the transfer early
+    fn check(&mut self) -> Result<(), Self::Error>;
+
+    /// Turns the DMA requests of the peripheral off again, once the
+    /// channels are done
+    fn stop(&mut self);
+
+    /// Called by `Transfer::complete` as soon as the channels moved all
+    /// data, before the transfer is waited for
+    fn complete(&mut self) {}
+}
+
+/// A transfer in progress, it owns the buffer, the channels and the
+/// peripheral until it's finished. Dropping it stops the channels, the
+/// buffer is not accessed by the PDMA afterwards.
+#[derive(Debug)]
+pub struct Transfer<BUF, CH, PAYLOAD>
+where
+    CH: Channels,
+    PAYLOAD: TransferPayload,
+{
+    // Only taken by `wait`
+    inner: Option<Inner<BUF, CH, PAYLOAD>>,
+}
+
+#[derive(Debug)]
+struct Inner<BUF, CH, PAYLOAD> {
+    buffer: BUF,
+    channels: CH,
+    payload: PAYLOAD,
+}
+
+impl<BUF, CH, PAYLOAD> Transfer<BUF, CH, PAYLOAD>
+where
+    CH: Channels,
+    PAYLOAD: TransferPayload,
+{
+    /// The channels have to be started already, after a release fence so
+    /// all writes to the buffer are visible to the PDMA
+    pub(crate) fn new(buffer: BUF, channels: CH, payload: PAYLOAD) -> Self {
+        Transfer {
+            inner: Some(Inner {
+                buffer,
+                channels,
+                payload,
+            }),
+        }
+    }
+
+    fn inner(&self) -> &Inner<BUF, CH, PAYLOAD> {
+        self.inner.as_ref().unwrap()
+    }
+
+    /// Whether the channels are done, either because all data was moved or
+    /// because of an error
+    pub fn is_done(&self) -> bool {
+        let channels = &self.inner().channels;
+        channels.is_complete() || channels.has_error()
+    }
+
+    /// Lets the peripheral react to the end of the transfer right away,
+    /// to be called from the transfer complete interrupt of the channels.
+    /// The interrupt is turned off again as its flag stays set until the
+    /// transfer is waited for. Returns whether the channels are complete.
+    pub fn complete(&mut self) -> bool {
+        let inner = self.inner.as_mut().unwrap();
+        let complete = inner.channels.is_complete();
+        if complete {
+            inner.channels.unlisten(Event::TransferComplete);
+            inner.payload.complete();
+        }
+        complete
+    }
+
+    /// Blocks until the transfer is finished and hands everything back,
+    /// together with the outcome
+    pub fn wait(mut self) -> (Result<(), PAYLOAD::Error>, BUF, CH, PAYLOAD) {
+        let mut inner = self.inner.take().unwrap();
+        let result = loop {
+            if let Err(e) = inner.payload.check() {
+                break Err(e);
+            } else if inner.channels.has_error() {
+                break Err(TransferError.into());
+            } else if inner.channels.is_complete() {
+                break Ok(());
+            }
+        };
+
+        inner.channels.stop();
+        inner.payload.stop();
+        // Reads of the buffer must not happen before the PDMA is done
+        compiler_fence(Ordering::Acquire);
+
+        (result, inner.buffer, inner.channels, inner.payload)
+    }
+}
+
+impl<BUF, CH, PAYLOAD> Drop for Transfer<BUF, CH, PAYLOAD>
+where
+    CH: Channels,
+    PAYLOAD: TransferPayload,
+{
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.channels.stop();
+            inner.payload.stop();
+            // The buffer is dropped or reused right after this
+            compiler_fence(Ordering::Acquire);
+        }
+    }
+}
+
+pub trait PdmaExt {
+    fn split(self) -> Parts;
+}
+
+macro_rules! pdma {
+    ($($CX:ident: ($cx:ident, $chxcr:ident, $chxsadr:ident, $chxdadr:ident, $chxtsr:ident,
+        ISR: [$tcistax:ident, $teistax:ident],
+        ISCR: [$geiclrx:ident, $beiclrx:ident, $hticlrx:ident, $tciclrx:ident, $teiclrx:ident],
+        IER: [$htiex:ident, $tciex:ident, $teiex:ident]),)+) => {
+        pub struct Parts {
+            $(
+                pub $cx: $CX,
+            )+
+        }
+
+        $(
+            #[derive(Debug)]
+            pub struct $CX {
+                _private: (),
+            }
+
+            impl Channel for $CX {
+                unsafe fn configure(&mut self, src: u32, src_inc: bool, dst: u32, dst_inc: bool, width: Width, len: u16) {
+                    let pdma = &*PDMA::ptr();
+                    let width = match width {
+                        Width::Byte => 0,
+                        Width::HalfWord => 1,
+                        Width::Word => 2,
+                    };
+
+                    pdma.$chxsadr.write(|w| w.bits(src));
+                    pdma.$chxdadr.write(|w| w.bits(dst));
+                    // Every request of the peripheral moves one block of
+                    // one unit, refer to User Manual page 239
+                    pdma.$chxtsr.write(|w| w.blklen().bits(1).blkcnt().bits(len));
+                    pdma.$chxcr.write(|w| {
+                        w.dwidth()
+                            .bits(width)
+                            .srcainc()
+                            .bit(src_inc)
+                            .dstainc()
+                            .bit(dst_inc)
+                    });
+                }
+
+                fn start(&mut self) {
+                    let pdma = unsafe { &*PDMA::ptr() };
+                    pdma.$chxcr.modify(|_, w| w.chen().set_bit());
+                }
+
+                fn stop(&mut self) {
+                    let pdma = unsafe { &*PDMA::ptr() };
+                    pdma.$chxcr.modify(|_, w| w.chen().clear_bit());
+                    // write 1 to clear
+                    pdma.pdma_iscr.write(|w| {
+                        w.$geiclrx()
+                            .set_bit()
+                            .$beiclrx()
+                            .set_bit()
+                            .$hticlrx()
+                            .set_bit()
+                            .$tciclrx()
+                            .set_bit()
+                            .$teiclrx()
+                            .set_bit()
+                    });
+                }
+
+                fn is_complete(&self) -> bool {
+                    let pdma = unsafe { &*PDMA::ptr() };
+                    pdma.pdma_isr.read().$tcistax().bit_is_set()
+                }
+
+                fn has_error(&self) -> bool {
+                    let pdma = unsafe { &*PDMA::ptr() };
+                    pdma.pdma_isr.read().$teistax().bit_is_set()
+                }
+
+                fn listen(&mut self, event: Event) {
+                    let pdma = unsafe { &*PDMA::ptr() };
+                    match event {
+                        Event::TransferComplete => pdma.pdma_ier.modify(|_, w| w.$tciex().set_bit()),
+                        Event::HalfTransfer => pdma.pdma_ier.modify(|_, w| w.$htiex().set_bit()),
+                        Event::TransferError => pdma.pdma_ier.modify(|_, w| w.$teiex().set_bit()),
+                    }
+                }
+
+                fn unlisten(&mut self, event: Event) {
+                    let pdma = unsafe { &*PDMA::ptr() };
+                    match event {
+                        Event::TransferComplete => pdma.pdma_ier.modify(|_, w| w.$tciex().clear_bit()),
+                        Event::HalfTransfer => pdma.pdma_ier.modify(|_, w| w.$htiex().clear_bit()),
+                        Event::TransferError => pdma.pdma_ier.modify(|_, w| w.$teiex().clear_bit()),
+                    }
+                }
+            }
+
+            impl Channels for $CX {
+                fn stop(&mut self) {
+                    Channel::stop(self)
+                }
+
+                fn is_complete(&self) -> bool {
+                    Channel::is_complete(self)
+                }
+
+                fn has_error(&self) -> bool {
+                    Channel::has_error(self)
+                }
+
+                fn unlisten(&mut self, event: Event) {
+                    Channel::unlisten(self, event)
+                }
+            }
+        )+
+
+        impl PdmaExt for PDMA {
+            fn split(self) -> Parts {
+                let ckcu = unsafe { &*CKCU::ptr() };
+                // enable the AHB clock for the PDMA
+                ckcu.ckcu_ahbccr.modify(|_, w| w.pdmaen().set_bit());
+
+                Parts {
+                    $(
+                        $cx: $CX { _private: () },
+                    )+
+                }
+            }
+        }
+    }
+}
+
+pdma! {
+    Ch0: (ch0, pdma_ch0cr, pdma_ch0sadr, pdma_ch0dadr, pdma_ch0tsr,
+        ISR: [tcista0, teista0],
+        ISCR: [geiclr0, beiclr0, hticlr0, tciclr0, teiclr0],
+        IER: [htie0, tcie0, teie0]),
+    Ch1: (ch1, pdma_ch1cr, pdma_ch1sadr, pdma_ch1dadr, pdma_ch1tsr,
+        ISR: [tcista1, teista1],
+        ISCR: [geiclr1, beiclr1, hticlr1, tciclr1, teiclr1],
+        IER: [htie1, tcie1, teie1]),
+    Ch2: (ch2, pdma_ch2cr, pdma_ch2sadr, pdma_ch2dadr, pdma_ch2tsr,
+        ISR: [tcista2, teista2],
+        ISCR: [geiclr2, beiclr2, hticlr2, tciclr2, teiclr2],
+        IER: [htie2, tcie2, teie2]),
+    Ch3: (ch3, pdma_ch3cr, pdma_ch3sadr, pdma_ch3dadr, pdma_ch3tsr,
+        ISR: [tcista3, teista3],
+        ISCR: [geiclr3, beiclr3, hticlr3, tciclr3, teiclr3],
+        IER: [htie3, tcie3, teie3]),
+    Ch4: (ch4, pdma_ch4cr, pdma_ch4sadr, pdma_ch4dadr, pdma_ch4tsr,
+        ISR: [tcista4, teista4],
+        ISCR: [geiclr4, beiclr4, hticlr4, tciclr4, teiclr4],
+        IER: [htie4, tcie4, teie4]),
+    Ch5: (ch5, pdma_ch5cr, pdma_ch5sadr, pdma_ch5dadr, pdma_ch5tsr,
+        ISR: [tcista5, teista5],
+        ISCR: [geiclr5, beiclr5, hticlr5, tciclr5, teiclr5],
+        IER: [htie5, tcie5, teie5]),
+}
@@ -8,6 +8,10 @@ pub use embedded_hal::prelude::*;
 pub use crate::ckcu::CkcuExt as _ht32f5xxxx_ckcu_CkcuExt;
 pub use crate::gpio::GpioExt as _ht32f5xxxx_gpio_GpioExt;
 pub use crate::i2c::I2cExt as _ht32f5xxxx_hal_i2c_I2cExt;
+pub use crate::i2s::I2sExt as _ht32f5xxxx_hal_i2s_I2sExt;
+pub use crate::pdma::PdmaExt as _ht32f5xxxx_hal_pdma_PdmaExt;
+pub use crate::sci::SciExt as _ht32f5xxxx_hal_sci_SciExt;
 pub use crate::serial::SerialExt as _ht32f5xxxx_hal_serial_SpiExt;
+pub use crate::serial::UsartExt as _ht32f5xxxx_hal_serial_UsartExt;
 pub use crate::spi::SpiExt as _ht32f5xxxx_hal_spi_SpiExt;
 pub use crate::time::U32Ext as _ht32f5xxxx_hal_time_U32Ext;
@@ -0,0 +1,588 @@
+//! Smart Card Interface (SCI) implementing ISO 7816-3 with the T=0 protocol
+//!
+//! The card reset and supply are not part of the SCI and have to be driven
+//! via GPIOs, only the card clock and the bidirectional data line are
+//! handled by the peripheral.
+use crate::ckcu::Clocks;
+use crate::hal::digital::v2::OutputPin;
+use crate::ht32::SCI;
+use crate::instance::Instance;
+use crate::time::U32Ext;
+
+#[non_exhaustive]
+#[derive(Debug)]
+pub enum Error {
+    /// A character still had a parity error after all retransmissions
+    Parity,
+    /// The card did not answer within the waiting time
+    Timeout,
+    /// The ATR was malformed, e.g. an invalid TS byte
+    InvalidAtr,
+    /// The card sent a procedure byte that is invalid for this command
+    Protocol,
+    /// The response does not fit into the provided buffer
+    BufferTooSmall,
+    /// The command APDU is malformed
+    InvalidCommand,
+    /// F / D is 0 or doesn't fit into the ETU register
+    InvalidEtu,
+}
+
+#[derive(Debug)]
+pub enum Event {
+    ParityError,
+    ReceiveComplete,
+    TransmitComplete,
+    WaitingTimeout,
+    CardPresenceChanged,
+    TxBufferEmpty,
+}
+
+pub trait PinClk<SCI> {}
+pub trait PinDio<SCI> {}
+
+pub mod config {
+    use crate::time::{Hertz, U32Ext};
+
+    /// How many times a character is retransmitted after the receiver
+    /// signalled a parity error via a NACK, refer to ISO 7816-3 7.3
+    pub enum Retries {
+        Four,
+        Five,
+    }
+
+    pub struct Config {
+        /// The card clock, ISO 7816-3 allows 1 to 5 Mhz during activation
+        pub clock: Hertz,
+        /// Extra guard time N in ETU, as announced in TC1 of the ATR
+        pub extra_guard_time: u8,
+        pub retries: Retries,
+        /// The waiting time in ETU, 9600 ETU is the initial waiting time
+        pub waiting_time: u32,
+    }
+
+    impl Config {
+        pub fn clock<F>(mut self, clock: F) -> Self
+        where
+            F: Into<Hertz>,
+        {
+            self.clock = clock.into();
+            self
+        }
+
+        pub fn extra_guard_time(mut self, etu: u8) -> Self {
+            self.extra_guard_time = etu;
+            self
+        }
+
+        pub fn retries(mut self, retries: Retries) -> Self {
+            self.retries = retries;
+            self
+        }
+
+        pub fn waiting_time(mut self, etu: u32) -> Self {
+            self.waiting_time = etu;
+            self
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum InvalidConfig {
+        /// The card clock is above 5 Mhz or half of PCLK
+        FrequencyTooHigh,
+        /// The card clock is 0 or lower than the maximum prescaler allows
+        FrequencyTooLow,
+        /// The waiting time doesn't fit into the 24 bit counter
+        WaitingTimeTooLong,
+    }
+
+    impl Default for Config {
+        fn default() -> Config {
+            Config {
+                clock: 4.mhz().into(),
+                extra_guard_time: 0,
+                retries: Retries::Four,
+                waiting_time: 9600,
+            }
+        }
+    }
+}
+
+/// Maximum length of an Answer To Reset according to ISO 7816-3 8.2.1
+pub const ATR_MAX_LEN: usize = 33;
+
+/// The encoding convention announced by the TS byte
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Convention {
+    Direct,
+    Inverse,
+}
+
+/// Answer To Reset as sent by the card after activation
+#[derive(Debug, Clone, Copy)]
+pub struct Atr {
+    bytes: [u8; ATR_MAX_LEN],
+    len: usize,
+    historical: usize,
+    tck: bool,
+    pub convention: Convention,
+}
+
+impl Atr {
+    /// All bytes of the ATR including TS and TCK
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// The historical bytes T1 ... TK
+    pub fn historical_bytes(&self) -> &[u8] {
+        let end = self.len - self.tck as usize;
+        &self.bytes[end - self.historical..end]
+    }
+}
+
+/// The status word SW1 SW2 a card finishes every command with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatusWord(pub u8, pub u8);
+
+impl StatusWord {
+    /// 90 00, normal processing
+    pub fn is_ok(&self) -> bool {
+        self.0 == 0x90 && self.1 == 0x00
+    }
+}
+
+/// A command APDU, refer to ISO 7816-4 5.1
+#[derive(Debug, Clone, Copy)]
+pub struct Command<'a> {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    /// Command data, at most 255 bytes in T=0
+    pub data: &'a [u8],
+    /// Number of expected response bytes, `Some(0)` means 256
+    pub le: Option<u8>,
+}
+
+#[derive(Debug)]
+pub struct SmartCard<SCI> {
+    sci: SCI,
+}
+
+pub trait SciExt<SCI>: Sized {
+    fn sci<CLK, DIO>(
+        self,
+        clk: CLK,
+        dio: DIO,
+        config: config::Config,
+        clocks: &Clocks,
+    ) -> Result<SmartCard<SCI>, config::InvalidConfig>
+    where
+        CLK: PinClk<SCI>,
+        DIO: PinDio<SCI>;
+
+    fn sci_unchecked(self, config: config::Config, clocks: &Clocks) -> Result<SmartCard<SCI>, config::InvalidConfig>;
+}
+
+/// The ETU of a freshly reset card is Fd / Dd = 372 card clock cycles
+const DEFAULT_ETU: u16 = 372;
+
+/// Width of the ETU field in SCI_ETUR
+const MAX_ETU: u16 = 0x7ff;
+
+/// Width of the PSC field in SCI_PSCR
+const MAX_PSC: u32 = 0x3f;
+
+/// Width of the WT field in SCI_WTR
+const MAX_WAITING_TIME: u32 = 0xff_ffff;
+
+/// Minimum number of card clock cycles RST has to stay low after the clock
+/// was started, refer to ISO 7816-3 6.2.2
+const RESET_CYCLES: u32 = 400;
+
+macro_rules! sci {
+    ($($SCIX:ident: $sciX:ident,)+) => {
+        $(
+            impl SmartCard<$SCIX> {
+                /// Creates a new smart card interface, the card clock is not
+                /// started until `activate` is called
+                pub fn $sciX(sci: $SCIX, config: config::Config, clocks: &Clocks) -> Result<Self, config::InvalidConfig> {
+                    if config.clock.0 == 0 {
+                        return Err(config::InvalidConfig::FrequencyTooLow);
+                    }
+                    if config.clock > 5.mhz().into() || config.clock.0 > clocks.pclk.0 / 2 {
+                        return Err(config::InvalidConfig::FrequencyTooHigh);
+                    }
+                    if config.waiting_time > MAX_WAITING_TIME {
+                        return Err(config::InvalidConfig::WaitingTimeTooLong);
+                    }
+
+                    // CK_SCI = PCLK / (2 * (PSC + 1)) according to
+                    // User Manual page 574
+                    // -> PSC = PCLK / (2 * CK_SCI) - 1, rounded up so the
+                    // card clock rather gets slower than faster
+                    let divider = 2 * config.clock.0 as u64;
+                    let psc = (clocks.pclk.0 as u64).div_ceil(divider) as u32 - 1;
+                    if psc > MAX_PSC {
+                        return Err(config::InvalidConfig::FrequencyTooLow);
+                    }
+
+                    // reset the SCI before using it
+                    $SCIX::reset();
+                    // enable the APB clock for the SCI
+                    $SCIX::enable();
+
+                    sci.sci_pscr.write(|w| unsafe { w.psc().bits(psc as u8) });
+
+                    sci.sci_etur.write(|w| unsafe { w.etu().bits(DEFAULT_ETU) });
+
+                    // The guard time is 12 ETU + N, refer to ISO 7816-3 7.2
+                    sci.sci_gtr.write(|w| unsafe { w.gt().bits(12 + config.extra_guard_time as u16) });
+                    sci.sci_wtr.write(|w| unsafe { w.wt().bits(config.waiting_time) });
+
+                    let retry5 = match config.retries {
+                        config::Retries::Four => false,
+                        config::Retries::Five => true,
+                    };
+
+                    sci.sci_cr.modify(|_, w| {
+                        w.retry()
+                            .bit(retry5)
+                            // retransmit characters the card NACKed
+                            .crep()
+                            .set_bit()
+                            // direct convention until the TS byte told us otherwise
+                            .conv()
+                            .clear_bit()
+                            .ensci()
+                            .set_bit()
+                    });
+
+                    Ok(SmartCard { sci })
+                }
+
+                pub fn free(self) -> $SCIX {
+                    self.sci
+                }
+
+                pub fn listen(&mut self, event: Event) {
+                    match event {
+                        Event::ParityError => self.sci.sci_ier.modify(|_, w| w.pare().set_bit()),
+                        Event::ReceiveComplete => self.sci.sci_ier.modify(|_, w| w.rxce().set_bit()),
+                        Event::TransmitComplete => self.sci.sci_ier.modify(|_, w| w.txce().set_bit()),
+                        Event::WaitingTimeout => self.sci.sci_ier.modify(|_, w| w.wte().set_bit()),
+                        Event::CardPresenceChanged => self.sci.sci_ier.modify(|_, w| w.cardire().set_bit()),
+                        Event::TxBufferEmpty => self.sci.sci_ier.modify(|_, w| w.txbee().set_bit()),
+                    }
+                }
+
+                pub fn unlisten(&mut self, event: Event) {
+                    match event {
+                        Event::ParityError => self.sci.sci_ier.modify(|_, w| w.pare().clear_bit()),
+                        Event::ReceiveComplete => self.sci.sci_ier.modify(|_, w| w.rxce().clear_bit()),
+                        Event::TransmitComplete => self.sci.sci_ier.modify(|_, w| w.txce().clear_bit()),
+                        Event::WaitingTimeout => self.sci.sci_ier.modify(|_, w| w.wte().clear_bit()),
+                        Event::CardPresenceChanged => self.sci.sci_ier.modify(|_, w| w.cardire().clear_bit()),
+                        Event::TxBufferEmpty => self.sci.sci_ier.modify(|_, w| w.txbee().clear_bit()),
+                    }
+                }
+
+                /// Runs the cold reset sequence and receives the ATR.
+                ///
+                /// The card has to be powered already, `rst` is the GPIO
+                /// connected to the RST contact of the card.
+                pub fn activate<RST>(&mut self, rst: &mut RST) -> Result<Atr, Error>
+                where
+                    RST: OutputPin,
+                {
+                    rst.set_low().ok();
+
+                    // Feed the card clock from the prescaler, refer to
+                    // User Manual page 575
+                    self.sci.sci_ccr.modify(|_, w| w.clksel().set_bit());
+
+                    // Keep RST low for at least 400 card clock cycles, one
+                    // card clock cycle takes 2 * (PSC + 1) HCLK cycles
+                    let psc = self.sci.sci_pscr.read().psc().bits() as u32;
+                    cortex_m::asm::delay(RESET_CYCLES * 2 * (psc + 1));
+
+                    rst.set_high().ok();
+                    self.restart_waiting_time();
+
+                    self.receive_atr()
+                }
+
+                /// Deactivates the card, it has to be powered down afterwards
+                pub fn deactivate<RST>(&mut self, rst: &mut RST)
+                where
+                    RST: OutputPin,
+                {
+                    rst.set_low().ok();
+                    // Stop the card clock in the low state
+                    self.sci.sci_ccr.modify(|_, w| w.cclk().clear_bit().clksel().clear_bit());
+                }
+
+                /// Changes the ETU after a PPS exchange or according to TA1,
+                /// `f` and `d` being the clock rate conversion and baud rate
+                /// adjustment integers
+                pub fn set_etu(&mut self, f: u16, d: u16) -> Result<(), Error> {
+                    let etu = f.checked_div(d).ok_or(Error::InvalidEtu)?;
+                    if etu == 0 || etu > MAX_ETU {
+                        return Err(Error::InvalidEtu);
+                    }
+                    self.sci.sci_etur.write(|w| unsafe { w.etu().bits(etu) });
+                    Ok(())
+                }
+
+                /// Changes the extra guard time N, e.g. according to TC1
+                pub fn set_extra_guard_time(&mut self, etu: u8) {
+                    self.sci.sci_gtr.write(|w| unsafe { w.gt().bits(12 + etu as u16) });
+                }
+
+                /// Sends a command APDU with the T=0 protocol and stores the
+                /// response data in `response`. Returns the amount of
+                /// response bytes as well as the status word.
+                ///
+                /// Commands with both data and `le` are sent without `le`
+                /// as T=0 requires, the card then answers with 61 xx and
+                /// the response has to be fetched with GET RESPONSE.
+                pub fn transmit(&mut self, command: &Command, response: &mut [u8]) -> Result<(usize, StatusWord), Error> {
+                    if command.data.len() > 255 {
+                        return Err(Error::InvalidCommand);
+                    }
+
+                    // P3 is either Lc or Le, refer to ISO 7816-3 10.3.2
+                    let p3 = if !command.data.is_empty() {
+                        command.data.len() as u8
+                    } else {
+                        command.le.unwrap_or(0)
+                    };
+                    let expected = match (command.data.is_empty(), command.le) {
+                        (true, Some(0)) => 256,
+                        (true, Some(le)) => le as usize,
+                        _ => 0,
+                    };
+
+                    for byte in &[command.cla, command.ins, command.p1, command.p2, p3] {
+                        self.write_byte(*byte)?;
+                    }
+
+                    let mut sent = 0;
+                    let mut received = 0;
+                    loop {
+                        let procedure = self.read_byte()?;
+                        match procedure {
+                            // NULL, the card needs more time
+                            0x60 => continue,
+                            // SW1, the status word follows
+                            0x61..=0x6f | 0x90..=0x9f => {
+                                let sw2 = self.read_byte()?;
+                                return Ok((received, StatusWord(procedure, sw2)));
+                            }
+                            // INS, all remaining data
+                            ins if ins == command.ins => {
+                                if sent < command.data.len() {
+                                    for byte in &command.data[sent..] {
+                                        self.write_byte(*byte)?;
+                                    }
+                                    sent = command.data.len();
+                                } else {
+                                    while received < expected {
+                                        let byte = self.read_byte()?;
+                                        *response.get_mut(received).ok_or(Error::BufferTooSmall)? = byte;
+                                        received += 1;
+                                    }
+                                }
+                            }
+                            // complement of INS, a single data byte
+                            ins if ins == !command.ins => {
+                                if sent < command.data.len() {
+                                    self.write_byte(command.data[sent])?;
+                                    sent += 1;
+                                } else if received < expected {
+                                    let byte = self.read_byte()?;
+                                    *response.get_mut(received).ok_or(Error::BufferTooSmall)? = byte;
+                                    received += 1;
+                                } else {
+                                    return Err(Error::Protocol);
+                                }
+                            }
+                            _ => return Err(Error::Protocol),
+                        }
+                    }
+                }
+
+                fn restart_waiting_time(&mut self) {
+                    // Toggling WTEN reloads the waiting time counter, refer
+                    // to User Manual page 571
+                    self.sci.sci_cr.modify(|_, w| w.wten().clear_bit());
+                    // write 1 to clear
+                    self.sci.sci_sr.write(|w| w.wtf().set_bit());
+                    self.sci.sci_cr.modify(|_, w| w.wten().set_bit());
+                }
+
+                /// Waits until `done` returns true, gives up once the
+                /// waiting time elapsed
+                fn wait<F>(&mut self, done: F) -> Result<(), Error>
+                where
+                    F: Fn(&crate::ht32::sci::sci_sr::R) -> bool,
+                {
+                    loop {
+                        let sr = self.sci.sci_sr.read();
+                        if done(&sr) {
+                            return Ok(());
+                        } else if sr.wtf().bit_is_set() {
+                            self.sci.sci_sr.write(|w| w.wtf().set_bit());
+                            return Err(Error::Timeout);
+                        }
+                    }
+                }
+
+                fn write_byte(&mut self, byte: u8) -> Result<(), Error> {
+                    self.restart_waiting_time();
+                    self.wait(|sr| sr.txbef().bit_is_set())?;
+                    self.sci.sci_txb.write(|w| unsafe { w.tb().bits(byte) });
+                    self.wait(|sr| sr.txcf().bit_is_set())?;
+
+                    // The card NACKed the character more often than allowed
+                    if self.sci.sci_sr.read().parf().bit_is_set() {
+                        // write 1 to clear
+                        self.sci.sci_sr.write(|w| w.parf().set_bit());
+                        return Err(Error::Parity);
+                    }
+
+                    self.restart_waiting_time();
+                    Ok(())
+                }
+
+                fn read_byte(&mut self) -> Result<u8, Error> {
+                    loop {
+                        let sr = self.sci.sci_sr.read();
+
+                        if sr.parf().bit_is_set() {
+                            self.sci.sci_sr.write(|w| w.parf().set_bit());
+                            return Err(Error::Parity);
+                        } else if sr.rxcf().bit_is_set() {
+                            let byte = self.sci.sci_rxb.read().rb().bits();
+                            self.restart_waiting_time();
+                            return Ok(byte);
+                        } else if sr.wtf().bit_is_set() {
+                            self.sci.sci_sr.write(|w| w.wtf().set_bit());
+                            return Err(Error::Timeout);
+                        }
+                    }
+                }
+
+                fn receive_atr(&mut self) -> Result<Atr, Error> {
+                    let mut atr = Atr {
+                        bytes: [0; ATR_MAX_LEN],
+                        len: 0,
+                        historical: 0,
+                        tck: false,
+                        convention: Convention::Direct,
+                    };
+
+                    // TS is 0x3B in direct convention, in inverse convention
+                    // 0x3F is sent which reads as 0x03 in direct convention,
+                    // refer to ISO 7816-3 8.1
+                    let ts = match self.read_byte()? {
+                        0x3b => 0x3b,
+                        0x03 => {
+                            self.sci.sci_cr.modify(|_, w| w.conv().set_bit());
+                            atr.convention = Convention::Inverse;
+                            0x3f
+                        }
+                        _ => return Err(Error::InvalidAtr),
+                    };
+                    atr.bytes[0] = ts;
+                    atr.len = 1;
+
+                    let push = |atr: &mut Atr, byte: u8| -> Result<(), Error> {
+                        *atr.bytes.get_mut(atr.len).ok_or(Error::InvalidAtr)? = byte;
+                        atr.len += 1;
+                        Ok(())
+                    };
+
+                    // T0 and every TDi announce which interface bytes follow
+                    // in their upper nibble, refer to ISO 7816-3 8.2.2
+                    let t0 = self.read_byte()?;
+                    push(&mut atr, t0)?;
+                    atr.historical = (t0 & 0x0f) as usize;
+
+                    let mut y = t0 >> 4;
+                    while y != 0 {
+                        for bit in 0..3 {
+                            if y & (1 << bit) != 0 {
+                                let byte = self.read_byte()?;
+                                push(&mut atr, byte)?;
+                            }
+                        }
+
+                        if y & 0b1000 != 0 {
+                            let td = self.read_byte()?;
+                            push(&mut atr, td)?;
+                            // TCK is present if any protocol other than T=0
+                            // is announced
+                            atr.tck |= td & 0x0f != 0;
+                            y = td >> 4;
+                        } else {
+                            y = 0;
+                        }
+                    }
+
+                    for _ in 0..atr.historical + atr.tck as usize {
+                        let byte = self.read_byte()?;
+                        push(&mut atr, byte)?;
+                    }
+
+                    // The XOR of T0 up to and including TCK is 0
+                    if atr.tck && atr.bytes[1..atr.len].iter().fold(0, |acc, b| acc ^ b) != 0 {
+                        return Err(Error::InvalidAtr);
+                    }
+
+                    Ok(atr)
+                }
+            }
+
+            impl SciExt<$SCIX> for $SCIX {
+                fn sci<CLK, DIO>(
+                    self,
+                    _clk: CLK,
+                    _dio: DIO,
+                    config: config::Config,
+                    clocks: &Clocks,
+                ) -> Result<SmartCard<$SCIX>, config::InvalidConfig>
+                where
+                    CLK: PinClk<$SCIX>,
+                    DIO: PinDio<$SCIX>
+                {
+                    SmartCard::$sciX(self, config, clocks)
+                }
+
+                fn sci_unchecked(self, config: config::Config, clocks: &Clocks) -> Result<SmartCard<$SCIX>, config::InvalidConfig> {
+                    SmartCard::$sciX(self, config, clocks)
+                }
+            }
+        )+
+    }
+}
+
+// Expanded by the table in `instance`, the pins, their modes and the
+// alternate functions are resolved there
+macro_rules! pins {
+    ($SCIX:ty: CLK: [$($CLK:ident),*], DIO: [$($DIO:ident),*],) => {
+        $(
+            impl $crate::sci::PinClk<$SCIX> for $CLK<Output<PushPull>, AF8> {}
+        )*
+        $(
+            impl $crate::sci::PinDio<$SCIX> for $DIO<Output<OpenDrain>, AF8> {}
+        )*
+    }
+}
+
+pub(crate) use pins;
+
+sci! {
+    SCI: sci,
+}
@@ -1,26 +1,29 @@
 //! Serial bus UART and USART
 use crate::ckcu::Clocks;
-use crate::gpio::{
-    gpioa::{PA10, PA14, PA15, PA2, PA3, PA4, PA5, PA8},
-    gpiob::{PB0, PB1, PB15, PB2, PB3, PB4, PB5, PB6, PB8},
-    gpioc::{PC0, PC1, PC12, PC13, PC3, PC4, PC5, PC6, PC7},
-    Floating, Input, Output, PushPull, AF6,
-};
 use crate::hal::blocking::serial as serial_block;
+use crate::hal::digital::v2::InputPin;
 use crate::hal::serial;
 use crate::hal::serial::Write;
-use crate::ht32::{CKCU, RSTCU, UART0, UART1, USART0, USART1};
+pub use crate::hal::spi::{Mode, Phase, Polarity, MODE_0, MODE_1, MODE_2, MODE_3};
+use crate::ht32::{UART0, UART1, USART0, USART1};
+use crate::instance::Instance;
+use crate::time::Bps;
 use core::convert::Infallible;
 use core::marker::PhantomData;
 use core::ptr;
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
 use nb::block;
 
 #[non_exhaustive]
 #[derive(Debug)]
 pub enum Error {
     Framing,
-    Parity,
+    /// The received word failed the parity check, it is returned nevertheless,
+    /// masked to the configured word length
+    Parity(u16),
     Overrun,
+    /// A break condition was detected on the RX line
+    Break,
 }
 
 #[derive(Debug)]
@@ -31,15 +34,204 @@ pub enum Event {
     TransmitComplete,
     TransmitRegisterEmpty,
     ReceiveDataReady,
+    /// A break condition was detected on the RX line
+    BreakDetected,
+    /// The RX line stayed idle for one frame after a received word. Only
+    /// USART0 and USART1 detect this in hardware, on UART0 and UART1
+    /// listening for it has no effect, use `Rx::wait_idle` instead.
+    Idle,
+}
+
+/// The character the remote end sends for baud rate detection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SyncCharacter {
+    /// Alternating bits, e.g. LIN
+    Sync55,
+    /// Used by many bootloader protocols
+    Sync7F,
+}
+
+#[derive(Debug)]
+pub enum AutoBaudError {
+    /// The measured bit timing does not match the sync character
+    SyncMismatch,
+    /// The detected baud rate is higher than the peripheral supports
+    BaudrateTooHigh,
+    /// The detected baud rate is lower than the divider allows
+    BaudrateTooLow,
+    /// No sync character was received in time
+    Timeout,
+}
+
+/// Counts HCLK cycles with SysTick beyond its 24 bit range, as long as it is
+/// read at least once per SysTick period
+struct Stopwatch<'a> {
+    syst: &'a mut SYST,
+    last: u32,
+    elapsed: u64,
+}
+
+impl<'a> Stopwatch<'a> {
+    // SysTick is a 24 bit down counter
+    const MAX: u32 = 0x00ff_ffff;
+
+    fn start(syst: &'a mut SYST) -> Self {
+        syst.set_clock_source(SystClkSource::Core);
+        syst.set_reload(Self::MAX);
+        syst.clear_current();
+        syst.enable_counter();
+        Stopwatch {
+            syst,
+            last: SYST::get_current(),
+            elapsed: 0,
+        }
+    }
+
+    fn elapsed(&mut self) -> u64 {
+        let now = SYST::get_current();
+        self.elapsed += (self.last.wrapping_sub(now) & Self::MAX) as u64;
+        self.last = now;
+        self.elapsed
+    }
+}
+
+impl Drop for Stopwatch<'_> {
+    fn drop(&mut self) {
+        self.syst.disable_counter();
+    }
+}
+
+/// Waits until `pin` has the level `high` and returns when that happened,
+/// or `None` once `timeout` HCLK cycles have elapsed in total
+fn wait_level<PIN>(pin: &PIN, high: bool, watch: &mut Stopwatch, timeout: u64) -> Option<u64>
+where
+    PIN: InputPin<Error = Infallible>,
+{
+    loop {
+        let now = watch.elapsed();
+        if pin.is_high().unwrap() == high {
+            return Some(now);
+        } else if now >= timeout {
+            return None;
+        }
+    }
+}
+
+/// Measures the time from the falling edge of the start bit to the last
+/// rising edge before the stop bit of a sync character in HCLK cycles.
+///
+/// Both 0x55 and 0x7F are sent LSB first and end in a low data bit, so this
+/// always spans 9 bit periods:
+/// 0x55: start 1 0 1 0 1 0 1 0 stop -> 5 rising edges
+/// 0x7F: start 1 1 1 1 1 1 1 0 stop -> 2 rising edges
+fn measure_sync<PIN>(pin: &PIN, syst: &mut SYST, sync: SyncCharacter, timeout: u64) -> Result<u32, AutoBaudError>
+where
+    PIN: InputPin<Error = Infallible>,
+{
+    let rising_edges = match sync {
+        SyncCharacter::Sync55 => 5,
+        SyncCharacter::Sync7F => 2,
+    };
+
+    let mut watch = Stopwatch::start(syst);
+    // Make sure we don't start in the middle of a character
+    wait_level(pin, true, &mut watch, timeout).ok_or(AutoBaudError::Timeout)?;
+    let start = wait_level(pin, false, &mut watch, timeout).ok_or(AutoBaudError::Timeout)?;
+
+    let mut start_bit = 0;
+    let mut end = start;
+    for edge in 0..rising_edges {
+        end = wait_level(pin, true, &mut watch, timeout).ok_or(AutoBaudError::Timeout)?;
+        if edge == 0 {
+            start_bit = end - start;
+        }
+        if edge != rising_edges - 1 {
+            wait_level(pin, false, &mut watch, timeout).ok_or(AutoBaudError::Timeout)?;
+        }
+    }
+    let total = end - start;
+
+    // The start bit has to be roughly one ninth of the whole measurement,
+    // otherwise this wasn't the character we are waiting for
+    let bit = total / 9;
+    if start_bit < bit - bit / 4 || start_bit > bit + bit / 4 {
+        return Err(AutoBaudError::SyncMismatch);
+    }
+
+    Ok(bit.min(u32::MAX as u64) as u32)
+}
+
+/// Waits until `pin` stayed high for `frame` HCLK cycles, returns false if
+/// that didn't happen within `timeout` cycles
+fn wait_idle_line<PIN>(pin: &PIN, syst: &mut SYST, frame: u64, timeout: u64) -> bool
+where
+    PIN: InputPin<Error = Infallible>,
+{
+    let mut watch = Stopwatch::start(syst);
+    let mut high_since = 0;
+    loop {
+        let now = watch.elapsed();
+        if pin.is_low().unwrap() {
+            high_since = now;
+        } else if now - high_since >= frame {
+            return true;
+        }
+        if now >= timeout {
+            return false;
+        }
+    }
 }
 
 pub trait PinTx<SERIAL> {}
 pub trait PinRx<SERIAL> {}
+/// The clock output pin used in synchronous mode
+pub trait PinSck<SERIAL> {}
+/// The open drain TX pin used as the single data line in half duplex mode
+pub trait PinHalfDuplex<SERIAL> {}
+
+/// Asynchronous full duplex mode (type state)
+#[derive(Debug)]
+pub struct Asynchronous;
+
+/// Synchronous master mode, the clock is driven on the SCK pin (type state)
+#[derive(Debug)]
+pub struct Synchronous;
+
+/// Single wire half duplex mode (type state)
+///
+/// The TX pin is driven open drain and has to be connected to the RX pin on
+/// the board, the receiver is switched off while transmitting so the
+/// transmitted data is not echoed back.
+#[derive(Debug)]
+pub struct HalfDuplex;
 
+/// IrDA SIR mode (type state)
 #[derive(Debug)]
-pub struct Serial<SERIAL, WORD = u8> {
+pub struct IrDA;
+
+/// Modes in which the transmitter and the receiver can operate independently
+/// of each other, only these allow to `split` a `Serial`.
+pub trait FullDuplexMode {}
+
+impl FullDuplexMode for Asynchronous {}
+impl FullDuplexMode for Synchronous {}
+
+/// Number of receive errors that occured since the peripheral was set up,
+/// the counters wrap around on overflow.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ErrorCounters {
+    pub framing: u32,
+    pub parity: u32,
+    pub overrun: u32,
+    pub breaks: u32,
+}
+
+#[derive(Debug)]
+pub struct Serial<SERIAL, WORD = u8, MODE = Asynchronous> {
     serial: SERIAL,
+    errors: ErrorCounters,
     _word: PhantomData<WORD>,
+    _mode: PhantomData<MODE>,
 }
 
 #[derive(Debug)]
@@ -50,6 +242,7 @@ pub struct Tx<SERIAL, WORD> {
 
 #[derive(Debug)]
 pub struct Rx<SERIAL, WORD> {
+    errors: ErrorCounters,
     _serial: PhantomData<SERIAL>,
     _word: PhantomData<WORD>,
 }
@@ -132,11 +325,57 @@ pub mod config {
         }
     }
 
+    /// The power mode of the IrDA SIR encoder
+    pub enum IrdaPower {
+        /// Pulses are 3/16 of a bit period wide
+        Normal,
+        /// Pulses are 3 periods of the 1.8432 Mhz low power clock wide
+        LowPower,
+    }
+
+    pub struct IrdaConfig {
+        pub power: IrdaPower,
+        /// Invert the TX output
+        pub invert_tx: bool,
+        /// Invert the RX input
+        pub invert_rx: bool,
+    }
+
+    impl IrdaConfig {
+        pub fn power(mut self, power: IrdaPower) -> Self {
+            self.power = power;
+            self
+        }
+
+        pub fn invert_tx(mut self, invert: bool) -> Self {
+            self.invert_tx = invert;
+            self
+        }
+
+        pub fn invert_rx(mut self, invert: bool) -> Self {
+            self.invert_rx = invert;
+            self
+        }
+    }
+
+    impl Default for IrdaConfig {
+        fn default() -> IrdaConfig {
+            IrdaConfig {
+                power: IrdaPower::Normal,
+                invert_tx: false,
+                invert_rx: false,
+            }
+        }
+    }
+
     #[derive(Debug)]
     pub enum InvalidConfig {
         /// Thrown if the word length in the config does not match the word length
         /// in the type
         WordLengthMismatch,
+        /// Thrown if the baud rate can not be used in the requested mode,
+        /// e.g. IrDA SIR is limited to 115200 bps
+        BaudrateUnsupported,
     }
 
     impl Default for Config {
@@ -171,8 +410,53 @@ pub trait SerialExt<SERIAL, WORD> {
     ) -> Result<Serial<SERIAL, WORD>, config::InvalidConfig>;
 }
 
+/// Extension trait for the operating modes only the USARTs support
+pub trait UsartExt<USART, WORD> {
+    /// Single wire half duplex, e.g. for smart servos. The TX and RX pin
+    /// have to be connected to the same data line.
+    fn serial_half_duplex<TX, RX>(
+        self,
+        tx: TX,
+        rx: RX,
+        config: config::Config,
+        clocks: &Clocks,
+    ) -> Result<Serial<USART, WORD, HalfDuplex>, config::InvalidConfig>
+    where
+        TX: PinHalfDuplex<USART>,
+        RX: PinRx<USART>;
+
+    /// IrDA SIR encoded communication with an infrared transceiver
+    fn serial_irda<TX, RX>(
+        self,
+        tx: TX,
+        rx: RX,
+        irda: config::IrdaConfig,
+        config: config::Config,
+        clocks: &Clocks,
+    ) -> Result<Serial<USART, WORD, IrDA>, config::InvalidConfig>
+    where
+        TX: PinTx<USART>,
+        RX: PinRx<USART>;
+
+    /// Synchronous master mode, the clock is only driven while transmitting
+    /// so in order to receive data dummy words have to be written.
+    fn serial_synchronous<TX, RX, SCK>(
+        self,
+        tx: TX,
+        rx: RX,
+        sck: SCK,
+        mode: Mode,
+        config: config::Config,
+        clocks: &Clocks,
+    ) -> Result<Serial<USART, WORD, Synchronous>, config::InvalidConfig>
+    where
+        TX: PinTx<USART>,
+        RX: PinRx<USART>,
+        SCK: PinSck<USART>;
+}
+
 macro_rules! serial {
-    ($($SERIALX:ident: ($serialX:ident, $serialXen:ident, $serialXrst:ident, $serial_cr:ident, $serial_dlr:ident, $serial_sifr:ident, $serial_dr:ident, $serial_ier:ident) => ($($WORD:ident),+),)+) => {
+    ($($SERIALX:ident: ($serialX:ident, $serial_cr:ident, $serial_dlr:ident, $serial_sifr:ident, $serial_dr:ident, $serial_ier:ident) => ($($WORD:ident),+),)+) => {
         $(
             $(
                 impl Serial<$SERIALX, $WORD> {
@@ -182,13 +466,10 @@ macro_rules! serial {
                         clocks: &Clocks,
                     ) -> Result<Serial<$SERIALX, $WORD>, config::InvalidConfig>
                     {
-                        let rstcu = unsafe { &*RSTCU::ptr() };
-                        let ckcu = unsafe { &*CKCU::ptr() };
-
                         // reset the serial port before using it
-                        rstcu.rstcu_apbprstr0.modify(|_, w| w.$serialXrst().set_bit());
+                        $SERIALX::reset();
                         // enable the APB clock for the serial port
-                        ckcu.ckcu_apbccr0.modify(|_, w| w.$serialXen().set_bit());
+                        $SERIALX::enable();
 
                         // According to User Manual page 528
                         // baud rate = ck_uart / brd
@@ -259,9 +540,11 @@ macro_rules! serial {
                         // enable TX and RX
                         serial.$serial_cr.modify(|_, w| w.urrxen().set_bit().urtxen().set_bit());
 
-                        Ok(Serial { serial, _word: PhantomData })
+                        Ok(Serial { serial, errors: ErrorCounters::default(), _word: PhantomData, _mode: PhantomData })
                     }
+                }
 
+                impl<MODE: FullDuplexMode> Serial<$SERIALX, $WORD, MODE> {
                     pub fn split(self) -> (Tx<$SERIALX, $WORD>, Rx<$SERIALX, $WORD>) {
                         (
                             Tx {
@@ -269,11 +552,19 @@ macro_rules! serial {
                                 _word: PhantomData
                             },
                             Rx {
+                                errors: self.errors,
                                 _serial: PhantomData,
                                 _word: PhantomData
                             },
                         )
                     }
+                }
+
+                impl<MODE> Serial<$SERIALX, $WORD, MODE> {
+                    /// Returns a snapshot of the receive error counters
+                    pub fn error_counters(&self) -> ErrorCounters {
+                        self.errors
+                    }
 
                     pub fn free(self) -> $SERIALX {
                         // Wait until the data register is empty to release the peripheral
@@ -291,10 +582,12 @@ macro_rules! serial {
                             Event::TransmitComplete => self.serial.$serial_ier.modify(|_, w| w.txcie().set_bit()),
                             Event::TransmitRegisterEmpty => self.serial.$serial_ier.modify(|_, w| w.txdeie().set_bit()),
                             Event::ReceiveDataReady => self.serial.$serial_ier.modify(|_, w| w.rxdrie().set_bit()),
+                            Event::BreakDetected => self.serial.$serial_ier.modify(|_, w| w.bie().set_bit()),
+                            Event::Idle => Self::set_idle(&self.serial, true),
                         }
                     }
 
-                    /// Starts listening for an interrupt event
+                    /// Stops listening for an interrupt event
                     pub fn unlisten(&mut self, event: Event) {
                         match event {
                             Event::FramingError => self.serial.$serial_ier.modify(|_, w| w.feie().clear_bit()),
@@ -303,6 +596,8 @@ macro_rules! serial {
                             Event::TransmitComplete => self.serial.$serial_ier.modify(|_, w| w.txcie().clear_bit()),
                             Event::TransmitRegisterEmpty => self.serial.$serial_ier.modify(|_, w| w.txdeie().clear_bit()),
                             Event::ReceiveDataReady => self.serial.$serial_ier.modify(|_, w| w.rxdrie().clear_bit()),
+                            Event::BreakDetected => self.serial.$serial_ier.modify(|_, w| w.bie().clear_bit()),
+                            Event::Idle => Self::set_idle(&self.serial, false),
                         }
                     }
                 }
@@ -332,48 +627,180 @@ macro_rules! serial {
                     }
                 }
 
-                impl serial::Read<$WORD> for Serial<$SERIALX, $WORD> {
+                impl<MODE: FullDuplexMode> serial::Read<$WORD> for Serial<$SERIALX, $WORD, MODE> {
                     type Error = Error;
 
                     fn read(&mut self) -> nb::Result<$WORD, Error> {
-                        let mut rx: Rx<$SERIALX, $WORD> = Rx {
-                            _serial: PhantomData,
-                            _word: PhantomData
-                        };
-                        rx.read()
+                        Rx::<$SERIALX, $WORD>::read_word(&mut self.errors)
                     }
                 }
 
-                impl serial::Read<$WORD> for Rx<$SERIALX, $WORD> {
-                    type Error = Error;
+                impl Rx<$SERIALX, $WORD> {
+                    /// Returns a snapshot of the receive error counters
+                    pub fn error_counters(&self) -> ErrorCounters {
+                        self.errors
+                    }
 
-                    fn read(&mut self) -> nb::Result<$WORD, Error> {
-                        let sifr = unsafe { (*$SERIALX::ptr()).$serial_sifr.read() };
+                    /// Reads a word from the data register, errors are
+                    /// reported and cleared in order of their severity
+                    fn read_word(errors: &mut ErrorCounters) -> nb::Result<$WORD, Error> {
+                        let serial = unsafe { &*$SERIALX::ptr() };
+                        let sifr = serial.$serial_sifr.read();
+
+                        // The data register is up to 9 bits wide, depending on
+                        // the configuration the parity bit ends up in the
+                        // uppermost bit so it has to be masked away.
+                        // Refer to User Manual page 532 for WLS values
+                        let pop = || {
+                            let mask = match serial.$serial_cr.read().wls().bits() {
+                                0b00 => 0x7f,
+                                0b01 => 0xff,
+                                _ => 0x1ff,
+                            };
+                            let data = unsafe {
+                                ptr::read_volatile(&serial.$serial_dr as *const _ as *const u32)
+                            };
+                            (data as u16) & mask
+                        };
 
-                        Err(if sifr.pei().bit_is_set() {
-                            nb::Error::Other(Error::Parity)
+                        // A break also shows up as a framing error with an
+                        // all zero data word, hence it has to be checked first.
+                        // All error flags are cleared by writing 1 to them.
+                        Err(if sifr.bii().bit_is_set() {
+                            // discard the zero word
+                            pop();
+                            serial.$serial_sifr.write(|w| w.bii().set_bit().fei().set_bit());
+                            errors.breaks = errors.breaks.wrapping_add(1);
+                            nb::Error::Other(Error::Break)
+                        }
+                        else if sifr.pei().bit_is_set() {
+                            let data = pop();
+                            serial.$serial_sifr.write(|w| w.pei().set_bit());
+                            errors.parity = errors.parity.wrapping_add(1);
+                            nb::Error::Other(Error::Parity(data))
                         }
                         else if sifr.fei().bit_is_set() {
+                            // the word is garbage without a proper stop bit
+                            pop();
+                            serial.$serial_sifr.write(|w| w.fei().set_bit());
+                            errors.framing = errors.framing.wrapping_add(1);
                             nb::Error::Other(Error::Framing)
                         }
                         else if sifr.oei().bit_is_set() {
+                            // the word in the data register is still valid,
+                            // only the following one got lost
+                            serial.$serial_sifr.write(|w| w.oei().set_bit());
+                            errors.overrun = errors.overrun.wrapping_add(1);
                             nb::Error::Other(Error::Overrun)
                         }
                         else if sifr.rxdr().bit_is_set() {
-                            return Ok(unsafe {
-                                    ptr::read_volatile(
-                                        &(*$SERIALX::ptr()).$serial_dr as *const _ as *const $WORD,
-                                    )
-                                }
-                            )
+                            return Ok(pop() as $WORD);
                         }
                         else {
                             nb::Error::WouldBlock
                         })
                     }
+
+                    /// Length of a frame in bit periods, as configured in the
+                    /// control register
+                    fn frame_bits() -> u8 {
+                        let cr = unsafe { (*$SERIALX::ptr()).$serial_cr.read() };
+                        // Refer to User Manual page 532 for WLS values
+                        let data = match cr.wls().bits() {
+                            0b00 => 7,
+                            0b01 => 8,
+                            _ => 9,
+                        };
+                        // start bit, data, parity and stop bits
+                        1 + data + cr.pbe().bit() as u8 + 1 + cr.nsb().bit() as u8
+                    }
+
+                    /// Detects the baud rate of the remote end by measuring a sync
+                    /// character on the RX pin with SysTick and reprograms the
+                    /// baud rate divider accordingly. This blocks until the
+                    /// sync character has been received, or fails with
+                    /// `AutoBaudError::Timeout` after `timeout` µs.
+                    ///
+                    /// As the pin has to be read while the peripheral is using
+                    /// it, the `Serial` has to be created via `serial_unchecked`.
+                    pub fn auto_baud<PIN>(
+                        &mut self,
+                        pin: &PIN,
+                        syst: &mut SYST,
+                        sync: SyncCharacter,
+                        timeout: u32,
+                        clocks: &Clocks,
+                    ) -> Result<Bps, AutoBaudError>
+                    where
+                        PIN: PinRx<$SERIALX> + InputPin<Error = Infallible>
+                    {
+                        let serial = unsafe { &*$SERIALX::ptr() };
+
+                        // SysTick runs at HCLK, one bit period in HCLK cycles
+                        // is exactly the baud rate divider, refer to
+                        // User Manual page 528
+                        let timeout = clocks.hclk.0 as u64 * timeout as u64 / 1_000_000;
+                        let baud_div = measure_sync(pin, syst, sync, timeout)?;
+                        if baud_div < 16 {
+                            return Err(AutoBaudError::BaudrateTooHigh);
+                        }
+                        if baud_div > u16::MAX as u32 {
+                            return Err(AutoBaudError::BaudrateTooLow);
+                        }
+                        serial.$serial_dlr.write(|w| unsafe { w.brd().bits(baud_div as u16) });
+
+                        // The sync character was most likely received garbled
+                        // with the old baud rate, get rid of it
+                        let mut discarded = ErrorCounters::default();
+                        while !matches!(Self::read_word(&mut discarded), Err(nb::Error::WouldBlock)) {}
+
+                        Ok(Bps(clocks.hclk.0 / baud_div))
+                    }
+
+                    /// Blocks until the RX line stayed high for one frame,
+                    /// returns false if that didn't happen within `timeout` µs.
+                    /// This works on every instance, while `Event::Idle` is
+                    /// only detected by the USARTs. As the pin has to be read
+                    /// while the peripheral is using it, the `Serial` has to be
+                    /// created via `serial_unchecked`.
+                    pub fn wait_idle<PIN>(&mut self, pin: &PIN, syst: &mut SYST, timeout: u32, clocks: &Clocks) -> bool
+                    where
+                        PIN: PinRx<$SERIALX> + InputPin<Error = Infallible>
+                    {
+                        let serial = unsafe { &*$SERIALX::ptr() };
+
+                        // SysTick runs at HCLK, one bit period is BRD cycles
+                        let bit = serial.$serial_dlr.read().brd().bits() as u64;
+                        let frame = bit * Self::frame_bits() as u64;
+                        let timeout = clocks.hclk.0 as u64 * timeout as u64 / 1_000_000;
+                        wait_idle_line(pin, syst, frame, timeout)
+                    }
                 }
 
-				impl serial::Write<$WORD> for Serial<$SERIALX, $WORD> {
+                impl Tx<$SERIALX, $WORD> {
+                    /// Starts driving a break condition (TX held low) after the
+                    /// current frame, it lasts until `stop_break` is called.
+                    /// The caller is responsible for the timing, e.g. DMX512
+                    /// requires at least 88 µs and LIN at least 13 bit periods.
+                    pub fn send_break(&mut self) {
+                        unsafe { (*$SERIALX::ptr()).$serial_cr.modify(|_, w| w.bcb().set_bit()) };
+                    }
+
+                    /// Releases the TX line after `send_break`
+                    pub fn stop_break(&mut self) {
+                        unsafe { (*$SERIALX::ptr()).$serial_cr.modify(|_, w| w.bcb().clear_bit()) };
+                    }
+                }
+
+                impl serial::Read<$WORD> for Rx<$SERIALX, $WORD> {
+                    type Error = Error;
+
+                    fn read(&mut self) -> nb::Result<$WORD, Error> {
+                        Self::read_word(&mut self.errors)
+                    }
+                }
+
+				impl<MODE: FullDuplexMode> serial::Write<$WORD> for Serial<$SERIALX, $WORD, MODE> {
                 	type Error = Infallible;
 
                 	fn flush(&mut self) -> nb::Result<(), Infallible> {
@@ -393,7 +820,7 @@ macro_rules! serial {
                 	}
             	}
 
-            	impl serial_block::write::Default<$WORD> for Serial<$SERIALX, $WORD> {}
+            	impl<MODE: FullDuplexMode> serial_block::write::Default<$WORD> for Serial<$SERIALX, $WORD, MODE> {}
 
                 impl serial::Write<$WORD> for Tx<$SERIALX, $WORD> {
                     type Error = Infallible;
@@ -415,7 +842,7 @@ macro_rules! serial {
                         if sifr.txde().bit_is_set() {
                             unsafe {
                                 ptr::write_volatile(
-                                    &(*$SERIALX::ptr()).$serial_dr as *const _ as *mut $WORD,
+                                    ptr::addr_of!((*$SERIALX::ptr()).$serial_dr) as *mut $WORD,
                                     byte
                                 )
                             }
@@ -432,73 +859,297 @@ macro_rules! serial {
     }
 }
 
-macro_rules! serial_pins {
-    ($($SERIALX:ty: TX: [$($TX:ty),*] RX: [$($RX:ty),*])+) => {
+macro_rules! uart {
+    ($($UARTX:ident => ($($WORD:ident),+),)+) => {
         $(
             $(
-                impl PinTx<$SERIALX> for $TX {}
-            )*
+                impl<MODE> Serial<$UARTX, $WORD, MODE> {
+                    /// The UARTs have no RX timeout counter, see `Rx::wait_idle`
+                    fn set_idle(_serial: &$UARTX, _enable: bool) {}
+                }
+            )+
+        )+
+    }
+}
+
+macro_rules! usart {
+    ($($USARTX:ident: ($usartX:ident, $usart_cr:ident, $usart_sifr:ident, $usart_ier:ident, $usart_tpr:ident, $usart_ir_dacr:ident, $usart_syncr:ident) => ($($WORD:ident),+),)+) => {
+        $(
             $(
-                impl PinRx<$SERIALX> for $RX {}
-            )*
+                impl<MODE> Serial<$USARTX, $WORD, MODE> {
+                    fn into_mode<NEW>(self) -> Serial<$USARTX, $WORD, NEW> {
+                        Serial { serial: self.serial, errors: self.errors, _word: PhantomData, _mode: PhantomData }
+                    }
+
+                    /// Detects an idle line with the RX timeout counter, which
+                    /// counts bit periods since the last received word. The
+                    /// counter only runs while the RX FIFO holds data, so the
+                    /// last words of a message have to stay in the FIFO until
+                    /// the event, e.g. by reading it only then.
+                    fn set_idle(serial: &$USARTX, enable: bool) {
+                        if enable {
+                            // One frame without a start bit, refer to User
+                            // Manual page 539
+                            let bits = Rx::<$USARTX, $WORD>::frame_bits();
+                            serial.$usart_tpr.modify(|_, w| unsafe { w.rxtoc().bits(bits).rxtoen().set_bit() });
+                        } else {
+                            serial.$usart_tpr.modify(|_, w| w.rxtoen().clear_bit());
+                        }
+                        serial.$usart_ier.modify(|_, w| w.rxtoie().bit(enable));
+                    }
+                }
+
+                impl Rx<$USARTX, $WORD> {
+                    /// Returns whether an idle line was detected and clears
+                    /// the condition, see `Event::Idle`
+                    pub fn is_idle(&mut self) -> bool {
+                        let serial = unsafe { &*$USARTX::ptr() };
+
+                        if serial.$usart_sifr.read().rxtof().bit_is_set() {
+                            // write 1 to clear
+                            serial.$usart_sifr.write(|w| w.rxtof().set_bit());
+                            true
+                        } else {
+                            false
+                        }
+                    }
+                }
+
+                impl UsartExt<$USARTX, $WORD> for $USARTX {
+                    fn serial_half_duplex<TX, RX>(
+                        self,
+                        _tx: TX,
+                        _rx: RX,
+                        config: config::Config,
+                        clocks: &Clocks,
+                    ) -> Result<Serial<$USARTX, $WORD, HalfDuplex>, config::InvalidConfig>
+                    where
+                        TX: PinHalfDuplex<$USARTX>,
+                        RX: PinRx<$USARTX>
+                    {
+                        // Half duplex is a normal mode transfer, the only
+                        // difference is the receiver getting switched off
+                        // while we are transmitting, see `serial::Write` below
+                        let serial = Serial::<$USARTX, $WORD>::$usartX(self, config, clocks)?;
+                        Ok(serial.into_mode())
+                    }
+
+                    fn serial_irda<TX, RX>(
+                        self,
+                        _tx: TX,
+                        _rx: RX,
+                        irda: config::IrdaConfig,
+                        config: config::Config,
+                        clocks: &Clocks,
+                    ) -> Result<Serial<$USARTX, $WORD, IrDA>, config::InvalidConfig>
+                    where
+                        TX: PinTx<$USARTX>,
+                        RX: PinRx<$USARTX>
+                    {
+                        // IrDA SIR is specified up to 115.2 kbps
+                        if config.baudrate.0 > 115_200 {
+                            return Err(config::InvalidConfig::BaudrateUnsupported);
+                        }
+
+                        let serial = Serial::<$USARTX, $WORD>::$usartX(self, config, clocks)?;
+
+                        // The low power pulse width is 3 periods of
+                        // ck_uart / IRDAPSC which should be 1.8432 Mhz
+                        // Refer to User Manual page 540
+                        let psc = (clocks.hclk.0 / 1_843_200).max(1).min(255) as u8;
+                        let low_power = match irda.power {
+                            config::IrdaPower::Normal => false,
+                            config::IrdaPower::LowPower => true,
+                        };
+
+                        serial.serial.$usart_ir_dacr.modify(|_, w| unsafe {
+                            w.ir_dapsc()
+                                .bits(psc)
+                                .ir_dalp()
+                                .bit(low_power)
+                                .txinv()
+                                .bit(irda.invert_tx)
+                                .rxinv()
+                                .bit(irda.invert_rx)
+                                .txsel()
+                                // start out in receive mode
+                                .clear_bit()
+                                .ir_daen()
+                                .set_bit()
+                        });
+
+                        // Refer to User Manual page 530 for the mode values
+                        serial.serial.$usart_cr.modify(|_, w| unsafe { w.mode().bits(0b01) });
+
+                        Ok(serial.into_mode())
+                    }
+
+                    fn serial_synchronous<TX, RX, SCK>(
+                        self,
+                        _tx: TX,
+                        _rx: RX,
+                        _sck: SCK,
+                        mode: Mode,
+                        config: config::Config,
+                        clocks: &Clocks,
+                    ) -> Result<Serial<$USARTX, $WORD, Synchronous>, config::InvalidConfig>
+                    where
+                        TX: PinTx<$USARTX>,
+                        RX: PinRx<$USARTX>,
+                        SCK: PinSck<$USARTX>
+                    {
+                        let serial = Serial::<$USARTX, $WORD>::$usartX(self, config, clocks)?;
+
+                        // Refer to User Manual page 541
+                        serial.serial.$usart_syncr.modify(|_, w| {
+                            w.cpo()
+                                .bit(mode.polarity == Polarity::IdleHigh)
+                                .cps()
+                                .bit(mode.phase == Phase::CaptureOnSecondTransition)
+                                .clken()
+                                .set_bit()
+                        });
+
+                        // Refer to User Manual page 530 for the mode values
+                        serial.serial.$usart_cr.modify(|_, w| unsafe { w.mode().bits(0b11) });
+
+                        Ok(serial.into_mode())
+                    }
+                }
+
+                impl serial::Read<$WORD> for Serial<$USARTX, $WORD, HalfDuplex> {
+                    type Error = Error;
+
+                    fn read(&mut self) -> nb::Result<$WORD, Error> {
+                        if self.serial.$usart_cr.read().urrxen().bit_is_clear() {
+                            // Turn the line around once our own transmission
+                            // has left the shift register
+                            if self.serial.$usart_sifr.read().txc().bit_is_clear() {
+                                return Err(nb::Error::WouldBlock);
+                            }
+                            self.serial.$usart_cr.modify(|_, w| w.urrxen().set_bit());
+                        }
+
+                        Rx::<$USARTX, $WORD>::read_word(&mut self.errors)
+                    }
+                }
+
+                impl serial::Write<$WORD> for Serial<$USARTX, $WORD, HalfDuplex> {
+                    type Error = Infallible;
+
+                    fn flush(&mut self) -> nb::Result<(), Infallible> {
+                        let mut tx: Tx<$USARTX, $WORD> = Tx {
+                            _serial: PhantomData,
+                            _word: PhantomData
+                        };
+                        tx.flush()?;
+
+                        // Ready to receive the answer
+                        self.serial.$usart_cr.modify(|_, w| w.urrxen().set_bit());
+                        Ok(())
+                    }
+
+                    fn write(&mut self, byte: $WORD) -> nb::Result<(), Infallible> {
+                        // Don't receive our own transmission
+                        self.serial.$usart_cr.modify(|_, w| w.urrxen().clear_bit());
+
+                        let mut tx: Tx<$USARTX, $WORD> = Tx {
+                            _serial: PhantomData,
+                            _word: PhantomData
+                        };
+                        tx.write(byte)
+                    }
+                }
+
+                impl serial_block::write::Default<$WORD> for Serial<$USARTX, $WORD, HalfDuplex> {}
+
+                impl serial::Read<$WORD> for Serial<$USARTX, $WORD, IrDA> {
+                    type Error = Error;
+
+                    fn read(&mut self) -> nb::Result<$WORD, Error> {
+                        if self.serial.$usart_ir_dacr.read().txsel().bit_is_set() {
+                            // The transceiver can only either send or receive,
+                            // switch back once our transmission is done
+                            if self.serial.$usart_sifr.read().txc().bit_is_clear() {
+                                return Err(nb::Error::WouldBlock);
+                            }
+                            self.serial.$usart_ir_dacr.modify(|_, w| w.txsel().clear_bit());
+                        }
+
+                        Rx::<$USARTX, $WORD>::read_word(&mut self.errors)
+                    }
+                }
+
+                impl serial::Write<$WORD> for Serial<$USARTX, $WORD, IrDA> {
+                    type Error = Infallible;
+
+                    fn flush(&mut self) -> nb::Result<(), Infallible> {
+                        let mut tx: Tx<$USARTX, $WORD> = Tx {
+                            _serial: PhantomData,
+                            _word: PhantomData
+                        };
+                        tx.flush()?;
+
+                        self.serial.$usart_ir_dacr.modify(|_, w| w.txsel().clear_bit());
+                        Ok(())
+                    }
+
+                    fn write(&mut self, byte: $WORD) -> nb::Result<(), Infallible> {
+                        self.serial.$usart_ir_dacr.modify(|_, w| w.txsel().set_bit());
+
+                        let mut tx: Tx<$USARTX, $WORD> = Tx {
+                            _serial: PhantomData,
+                            _word: PhantomData
+                        };
+                        tx.write(byte)
+                    }
+                }
+
+                impl serial_block::write::Default<$WORD> for Serial<$USARTX, $WORD, IrDA> {}
+            )+
         )+
     }
 }
 
-serial_pins! {
-    UART0:
-        TX: [
-            PC4<Output<PushPull>, AF6>,
-            PB2<Output<PushPull>, AF6>,
-            PB6<Output<PushPull>, AF6>
-        ]
-        RX: [
-            PC5<Input<Floating>, AF6>,
-            PB3<Input<Floating>, AF6>,
-            PB8<Input<Floating>, AF6>
-        ]
-    UART1:
-        TX: [
-            PC12<Output<PushPull>, AF6>,
-            PB4<Output<PushPull>, AF6>,
-            PC1<Output<PushPull>, AF6>
-        ]
-        RX: [
-            PC13<Input<Floating>, AF6>,
-            PB5<Input<Floating>, AF6>,
-            PC3<Input<Floating>, AF6>
-        ]
-    USART0:
-        TX: [
-            PA2<Output<PushPull>, AF6>,
-            PC6<Output<PushPull>, AF6>,
-            PA8<Output<PushPull>, AF6>,
-            PB0<Output<PushPull>, AF6>
-        ]
-        RX: [
-            PA3<Input<Floating>, AF6>,
-            PC7<Input<Floating>, AF6>,
-            PA10<Input<Floating>, AF6>,
-            PB1<Input<Floating>, AF6>
-        ]
-    USART1:
-        TX: [
-            PA4<Output<PushPull>, AF6>,
-            PB15<Output<PushPull>, AF6>,
-            PA14<Output<PushPull>, AF6>
-        ]
-        RX: [
-            PA5<Input<Floating>, AF6>,
-            PC0<Input<Floating>, AF6>,
-            PA15<Input<Floating>, AF6>
-        ]
+// Expanded by the table in `instance`, the pins, their modes and the
+// alternate functions are resolved there
+macro_rules! pins {
+    ($USARTX:ty: TX: [$($TX:ident),*], RX: [$($RX:ident),*], SCK: [$($SCK:ident),*],) => {
+        $crate::serial::pins! { $USARTX: TX: [$($TX),*], RX: [$($RX),*], }
+        $(
+            impl $crate::serial::PinHalfDuplex<$USARTX> for $TX<Output<OpenDrain>, AF6> {}
+        )*
+        $(
+            impl $crate::serial::PinSck<$USARTX> for $SCK<Output<PushPull>, AF6> {}
+        )*
+    };
+    ($SERIALX:ty: TX: [$($TX:ident),*], RX: [$($RX:ident),*],) => {
+        $(
+            impl $crate::serial::PinTx<$SERIALX> for $TX<Output<PushPull>, AF6> {}
+        )*
+        $(
+            impl $crate::serial::PinRx<$SERIALX> for $RX<Input<Floating>, AF6> {}
+        )*
+    };
 }
 
+pub(crate) use pins;
+
 serial! {
-    UART0: (uart0, ur0en, ur0rst, uart_urcr, uart_urdlr, uart_ursifr, uart_urdr, uart_urier) => (u8, u16),
-    UART1: (uart1, ur1en, ur0rst, uart_urcr, uart_urdlr, uart_ursifr, uart_urdr, uart_urier) => (u8, u16),
-    USART0: (usart0, usr0en, usr0rst, usart_usrcr, usart_usrdlr, usart_usrsifr, usart_usrdr, usart_usrier) => (u8, u16),
-    USART1: (usart1, usr1en, usr1rst, usart_usrcr, usart_usrdlr, usart_usrsifr, usart_usrdr, usart_usrier) => (u8, u16),
+    UART0: (uart0, uart_urcr, uart_urdlr, uart_ursifr, uart_urdr, uart_urier) => (u8, u16),
+    UART1: (uart1, uart_urcr, uart_urdlr, uart_ursifr, uart_urdr, uart_urier) => (u8, u16),
+    USART0: (usart0, usart_usrcr, usart_usrdlr, usart_usrsifr, usart_usrdr, usart_usrier) => (u8, u16),
+    USART1: (usart1, usart_usrcr, usart_usrdlr, usart_usrsifr, usart_usrdr, usart_usrier) => (u8, u16),
+}
+
+uart! {
+    UART0 => (u8, u16),
+    UART1 => (u8, u16),
+}
+
+usart! {
+    USART0: (usart0, usart_usrcr, usart_usrsifr, usart_usrier, usart_usrtpr, usart_ir_dacr, usart_syncr) => (u8, u16),
+    USART1: (usart1, usart_usrcr, usart_usrsifr, usart_usrier, usart_usrtpr, usart_ir_dacr, usart_syncr) => (u8, u16),
 }
 
 impl<SERIAL> core::fmt::Write for Tx<SERIAL, u8>
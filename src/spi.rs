@@ -1,18 +1,21 @@
 //! Serial Peripheral Interface (SPI) bus
 use crate::ckcu::Clocks;
-use crate::gpio::{
-    gpioa::{PA0, PA1, PA11, PA15, PA2, PA4, PA5, PA6, PA9},
-    gpiob::{PB0, PB1, PB3, PB4, PB5, PB6},
-    gpioc::{PC0, PC11, PC12, PC13, PC2, PC3, PC5, PC8, PC9},
-    Floating, Input, Output, PushPull, AF5,
-};
 use crate::hal;
+use crate::hal::digital::v2::OutputPin;
 pub use crate::hal::spi::{Mode, Phase, Polarity, MODE_0, MODE_1, MODE_2, MODE_3};
-use crate::ht32::{CKCU, RSTCU, SPI0, SPI1};
+use crate::hal1;
+use crate::ht32::{SPI0, SPI1};
+use crate::instance::Instance;
+use crate::pdma::{self, ReadBuffer, Transfer, TransferPayload, WriteBuffer};
 use crate::time::Hertz;
-use core::convert::TryInto;
+use core::cell::{Cell, RefCell};
+use core::convert::Infallible;
+use core::fmt;
 use core::marker::PhantomData;
 use core::ptr;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+pub mod flash;
 
 #[non_exhaustive]
 #[derive(Debug)]
@@ -21,6 +24,28 @@ pub enum Error {
     Overrun,
     /// Write Collision occured
     WriteCollision,
+    /// The master deasserted SEL in the middle of a frame
+    SlaveAbort,
+    /// SEL was asserted by another master while in master mode
+    ModeFault,
+    /// The PDMA could not access a buffer
+    Dma,
+}
+
+impl From<pdma::TransferError> for Error {
+    fn from(_: pdma::TransferError) -> Error {
+        Error::Dma
+    }
+}
+
+impl hal1::spi::Error for Error {
+    fn kind(&self) -> hal1::spi::ErrorKind {
+        match self {
+            Error::Overrun => hal1::spi::ErrorKind::Overrun,
+            Error::ModeFault => hal1::spi::ErrorKind::ModeFault,
+            Error::WriteCollision | Error::SlaveAbort | Error::Dma => hal1::spi::ErrorKind::Other,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -31,11 +56,25 @@ pub enum Event {
     RxBufferNotEmpty,
     TxEmpty,
     TxBufferEmpty,
+    SlaveAbort,
 }
 
 pub trait PinSck<SPI> {}
 pub trait PinMiso<SPI> {}
 pub trait PinMosi<SPI> {}
+/// The slave select pin, driven by the master
+pub trait PinNss<SPI> {}
+/// The slave select pin when driven by the SPI in master mode
+pub trait PinSel<SPI> {}
+
+/// The PDMA channels that are wired to the SPI requests
+pub trait TxChannel<SPI>: pdma::Channel + pdma::Channels {}
+pub trait RxChannel<SPI>: pdma::Channel + pdma::Channels {}
+
+/// In slave mode the signal directions are reversed
+pub trait PinSckSlave<SPI> {}
+pub trait PinMisoSlave<SPI> {}
+pub trait PinMosiSlave<SPI> {}
 
 #[derive(Debug)]
 pub struct Spi<SPI, WORD = u8> {
@@ -43,6 +82,270 @@ pub struct Spi<SPI, WORD = u8> {
     _word: PhantomData<WORD>,
 }
 
+pub mod config {
+    use super::{Mode, MODE_0};
+    use crate::time::{Hertz, U32Ext};
+
+    pub enum BitOrder {
+        MsbFirst,
+        LsbFirst,
+    }
+
+    pub struct Config {
+        pub mode: Mode,
+        /// The desired SCK frequency, the closest one that is not higher
+        /// will be chosen
+        pub frequency: Hertz,
+        /// Length of a data frame in bits, 1 to 16. `None` uses the size of
+        /// the word type
+        pub frame_length: Option<u8>,
+        pub bit_order: BitOrder,
+        pub timing: SpiTiming,
+    }
+
+    /// Timing of the hardware driven SEL pin and the gap between frames,
+    /// all in SCK cycles. The time between asserting SEL and the first SCK
+    /// edge is fixed by the hardware.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct SpiTiming {
+        /// Time SEL stays asserted after the last frame, 0 to 15
+        pub sel_hold: u8,
+        /// Idle time inserted between two frames, 1 to 16. SEL stays
+        /// asserted during the gap
+        pub guard_time: Option<u8>,
+    }
+
+    impl SpiTiming {
+        pub fn sel_hold(mut self, cycles: u8) -> Self {
+            self.sel_hold = cycles;
+            self
+        }
+
+        pub fn guard_time(mut self, cycles: u8) -> Self {
+            self.guard_time = Some(cycles);
+            self
+        }
+    }
+
+    impl Config {
+        pub fn mode(mut self, mode: Mode) -> Self {
+            self.mode = mode;
+            self
+        }
+
+        pub fn frequency<F>(mut self, frequency: F) -> Self
+        where
+            F: Into<Hertz>,
+        {
+            self.frequency = frequency.into();
+            self
+        }
+
+        pub fn frame_length(mut self, bits: u8) -> Self {
+            self.frame_length = Some(bits);
+            self
+        }
+
+        pub fn msb_first(mut self) -> Self {
+            self.bit_order = BitOrder::MsbFirst;
+            self
+        }
+
+        pub fn lsb_first(mut self) -> Self {
+            self.bit_order = BitOrder::LsbFirst;
+            self
+        }
+
+        pub fn timing(mut self, timing: SpiTiming) -> Self {
+            self.timing = timing;
+            self
+        }
+    }
+
+    #[derive(Debug)]
+    pub enum InvalidConfig {
+        /// Thrown if the frame length is 0 or does not fit into the word
+        /// type
+        FrameLengthMismatch,
+        /// The SCK frequency can be at most half of PCLK
+        FrequencyTooHigh,
+        /// The SCK frequency is lower than the maximum divider allows
+        FrequencyTooLow,
+        /// One of the `SpiTiming` values is out of range
+        TimingOutOfRange,
+        /// Another device on the bus already uses the hardware SEL pin
+        HardwareCsTaken,
+    }
+
+    impl Default for Config {
+        fn default() -> Config {
+            Config {
+                mode: MODE_0,
+                frequency: 1.mhz().into(),
+                frame_length: None,
+                bit_order: BitOrder::MsbFirst,
+                timing: SpiTiming::default(),
+            }
+        }
+    }
+}
+
+/// The level of the hardware driven SEL pin while a slave is selected
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelActive {
+    Low,
+    High,
+}
+
+/// Marker for an `SpiDevice` whose chip select is the hardware SEL pin, the
+/// SPI asserts it automatically for every transfer.
+#[derive(Debug)]
+pub struct HardwareCs;
+
+/// Several `SpiDevice`s with their own chip select, mode and frequency
+/// sharing one `Spi`. The bus is reconfigured before every transaction of a
+/// device.
+///
+/// As it is based on a `RefCell` it can only be used from one execution
+/// context.
+#[derive(Debug)]
+pub struct SharedSpiBus<SPI, WORD = u8> {
+    spi: RefCell<Spi<SPI, WORD>>,
+    clocks: Clocks,
+    /// Whether a device uses the hardware SEL pin
+    hardware_cs_taken: Cell<bool>,
+}
+
+/// A single device on a `SharedSpiBus`
+pub struct SpiDevice<'a, SPI, WORD, CS> {
+    bus: &'a SharedSpiBus<SPI, WORD>,
+    cs: CS,
+    hardware_cs: Option<SelActive>,
+    mode: Mode,
+    spi_div: u16,
+}
+
+impl<'a, SPI, WORD, CS> fmt::Debug for SpiDevice<'a, SPI, WORD, CS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The embedded-hal 0.2 `Mode` doesn't implement `Debug`
+        f.debug_struct("SpiDevice")
+            .field("hardware_cs", &self.hardware_cs)
+            .field("cpol", &(self.mode.polarity == Polarity::IdleHigh))
+            .field("cpha", &(self.mode.phase == Phase::CaptureOnSecondTransition))
+            .field("spi_div", &self.spi_div)
+            .finish()
+    }
+}
+
+impl<SPI, WORD> hal1::spi::ErrorType for Spi<SPI, WORD> {
+    type Error = Error;
+}
+
+impl<'a, SPI, WORD, CS> hal1::spi::ErrorType for SpiDevice<'a, SPI, WORD, CS> {
+    type Error = Error;
+}
+
+impl OutputPin for HardwareCs {
+    type Error = Infallible;
+
+    // The SPI takes care of the pin
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+/// Number of entries in both the TX and the RX FIFO
+const FIFO_DEPTH: usize = 8;
+
+/// TXBE is set while the TX FIFO holds at most this many words
+const TX_FIFO_THRESHOLD: u8 = 0;
+
+/// RXBNE is set while the RX FIFO holds at least this many words
+const RX_FIFO_THRESHOLD: u8 = 1;
+
+/// Value for SPI_CPR for an SCK frequency
+///
+/// f_sck = f_pclk / (2 *  (CP + 1)) according to User Manual page 491
+/// -> CP = (f_pclk / (2 * f_sck)) - 1
+/// rounded up so SCK rather gets slower than faster
+fn spi_div(freq: Hertz, clocks: &Clocks) -> Result<u16, config::InvalidConfig> {
+    if freq.0 == 0 {
+        return Err(config::InvalidConfig::FrequencyTooLow);
+    }
+    if freq.0 > clocks.pclk.0 / 2 {
+        return Err(config::InvalidConfig::FrequencyTooHigh);
+    }
+    // 2 * f_sck doesn't fit into u32 above 2 Ghz
+    let divider = 2 * freq.0 as u64;
+    let cp = (clocks.pclk.0 as u64).div_ceil(divider) - 1;
+    if cp > u16::MAX as u64 {
+        return Err(config::InvalidConfig::FrequencyTooLow);
+    }
+
+    Ok(cp as u16)
+}
+
+/// An SPI peripheral that is clocked by an external master, responding with
+/// whatever was written via `send` beforehand.
+#[derive(Debug)]
+pub struct SpiSlave<SPI, WORD = u8> {
+    spi: SPI,
+    _word: PhantomData<WORD>,
+}
+
+/// Value of the FORMAT field in SPI_CR1 for an SPI mode
+///
+/// The values for the format register can be found at
+/// User Manual page 489, they follow this pattern
+/// from left to right:
+/// 1st bit = CPOL
+/// 2nd bit = CPOL ^ CPHA
+/// 3rd bit = !(CPOL ^ CPHA)
+fn format_bits(mode: Mode) -> u8 {
+    let cpol = (mode.polarity == Polarity::IdleHigh) as u8;
+    let cpha = (mode.phase == Phase::CaptureOnSecondTransition) as u8;
+    (cpol << 2) | ((cpol ^ cpha) << 1) | (!(cpol ^ cpha) & 1)
+}
+
+/// Checks that the `SpiTiming` fits into the 4 bit fields of SPI_CR0
+fn validate_timing(timing: &config::SpiTiming) -> Result<(), config::InvalidConfig> {
+    let guard_time_valid = timing.guard_time.is_none_or(|cycles| (1..=16).contains(&cycles));
+    if timing.sel_hold > 15 || !guard_time_valid {
+        return Err(config::InvalidConfig::TimingOutOfRange);
+    }
+    Ok(())
+}
+
+/// Reports the first pending error flag of an SPI and clears it, evaluates
+/// to the status register otherwise
+///
+/// All error flags are cleared by writing 1 to them, refer to
+/// User Manual page 495
+macro_rules! check_errors {
+    ($spi:expr) => {{
+        let sr = $spi.spi_sr.read();
+        if sr.mf().bit_is_set() {
+            $spi.spi_sr.write(|w| w.mf().set_bit());
+            Err(Error::ModeFault)
+        } else if sr.sa().bit_is_set() {
+            $spi.spi_sr.write(|w| w.sa().set_bit());
+            Err(Error::SlaveAbort)
+        } else if sr.ro().bit_is_set() {
+            $spi.spi_sr.write(|w| w.ro().set_bit());
+            Err(Error::Overrun)
+        } else if sr.wc().bit_is_set() {
+            $spi.spi_sr.write(|w| w.wc().set_bit());
+            Err(Error::WriteCollision)
+        } else {
+            Ok(sr)
+        }
+    }};
+}
+
 pub trait SpiExt<SPI, WORD>: Sized {
     fn spi<SCK, MISO, MOSI, F>(
         self,
@@ -62,41 +365,75 @@ pub trait SpiExt<SPI, WORD>: Sized {
     fn spi_unchecked<F>(self, mode: Mode, freq: F, clocks: &Clocks) -> Spi<SPI, WORD>
     where
         F: Into<Hertz>;
+
+    /// Like `spi` but with frame length and bit order control, fails instead
+    /// of panicking if the configuration can not be achieved
+    fn spi_with_config<SCK, MISO, MOSI>(
+        self,
+        sck: SCK,
+        miso: MISO,
+        mosi: MOSI,
+        config: config::Config,
+        clocks: &Clocks,
+    ) -> Result<Spi<SPI, WORD>, config::InvalidConfig>
+    where
+        SCK: PinSck<SPI>,
+        MISO: PinMiso<SPI>,
+        MOSI: PinMosi<SPI>;
+
+    fn spi_with_config_unchecked(
+        self,
+        config: config::Config,
+        clocks: &Clocks,
+    ) -> Result<Spi<SPI, WORD>, config::InvalidConfig>;
+
+    /// Sets the SPI up as a slave with hardware slave select
+    fn spi_slave<SCK, MISO, MOSI, NSS>(
+        self,
+        sck: SCK,
+        miso: MISO,
+        mosi: MOSI,
+        nss: NSS,
+        mode: Mode,
+    ) -> SpiSlave<SPI, WORD>
+    where
+        SCK: PinSckSlave<SPI>,
+        MISO: PinMisoSlave<SPI>,
+        MOSI: PinMosiSlave<SPI>,
+        NSS: PinNss<SPI>;
+
+    fn spi_slave_unchecked(self, mode: Mode) -> SpiSlave<SPI, WORD>;
 }
 
 macro_rules! spi {
-    ($($SPIX:ident: ($spiX:ident, $spiXen:ident, $spiXrst:ident) => ($($WORD:ident),+),)+) => {
+    ($($SPIX:ident: ($spiX:ident) => ($($WORD:ident),+),)+) => {
         $(
             $(
                 impl Spi<$SPIX, $WORD> {
-                    fn $spiX<F>(
+                    fn $spiX(
                         spi: $SPIX,
-                        mode: Mode,
-                        freq: F,
+                        config: config::Config,
                         clocks: &Clocks,
-                    ) -> Spi<$SPIX, $WORD>
-                    where
-                        F: Into<Hertz>
+                    ) -> Result<Spi<$SPIX, $WORD>, config::InvalidConfig>
                     {
-                        let rstcu = unsafe { &*RSTCU::ptr() };
-                        let ckcu = unsafe { &*CKCU::ptr() };
+                        let word_bits = (core::mem::size_of::<$WORD>() * 8) as u8;
+                        let frame_length = config.frame_length.unwrap_or(word_bits);
+                        if frame_length == 0 || frame_length > word_bits {
+                            return Err(config::InvalidConfig::FrameLengthMismatch);
+                        }
+                        let spi_div = spi_div(config.frequency, clocks)?;
+                        validate_timing(&config.timing)?;
+
                         // reset the SPI port before using it
-                        rstcu.rstcu_apbprstr0.modify(|_, w| w.$spiXrst().set_bit());
+                        $SPIX::reset();
                         // enable the APB clock for the SPI port
-                        ckcu.ckcu_apbccr0.modify(|_, w| w.$spiXen().set_bit());
-
-                        // The values for the format register can be found at
-                        // User Manual page 489, they follow this pattern
-                        // from left to right:
-                        // 1st bit = CPOL
-                        // 2nd bit = CPOL ^ CPHA
-                        // 3rd bit = !(CPOL ^ CPHA)
-                        let cpol = (mode.polarity == Polarity::IdleHigh) as u8;
-                        let cpha = (mode.phase == Phase::CaptureOnSecondTransition) as u8;
-                        let mode =
-                            (cpol << 2) |
-                            ((cpol ^ cpha) << 1) |
-                            (!(cpol ^ cpha));
+                        $SPIX::enable();
+
+                        let mode = format_bits(config.mode);
+                        let lsb_first = match config.bit_order {
+                            config::BitOrder::MsbFirst => false,
+                            config::BitOrder::LsbFirst => true,
+                        };
 
                         spi.spi_cr1.modify(|_, w| unsafe {
                             w.mode().
@@ -106,36 +443,121 @@ macro_rules! spi {
                                 // software SS
                                 clear_bit().
                                 firstbit().
-                                // MSB first
-                                clear_bit().
+                                bit(lsb_first).
                                 format().
                                 bits(mode).
                                 dfl().
-                                // data frame length
-                                bits((core::mem::size_of::<$WORD>()*8).try_into().unwrap())
+                                // data frame length, 16 bits are encoded as 0
+                                // refer to User Manual page 490
+                                bits(frame_length & 0xf)
                         });
 
-                        // f_sck = f_pclk / (2 *  (CP + 1)) according to User Manual page 491
-                        // -> CP = (f_pclk / (2 * f_sck)) - 1
-                        // for pclk = hclk
-                        let freq = freq.into();
-                        let spi_div: u16 = ((clocks.hclk.0 / (2 * freq.0)) - 1) as u16;
-
                         spi.spi_cpr.write(|w| unsafe { w.cp().bits(spi_div) });
 
+                        let mut spi = Spi::<$SPIX, $WORD> { spi, _word: PhantomData };
+                        spi.apply_timing(&config.timing);
+
                         // Select pin output enable
                         // This causes the chip to not mode fault all the time
                         // when it's not in a multi master setup.
-                        spi.spi_cr0.modify(|_, w| w.seloen().set_bit());
+                        spi.spi.spi_cr0.modify(|_, w| w.seloen().set_bit());
 
-                        spi.spi_cr0.modify(|_, w| w.spien().set_bit());
-                        Spi { spi, _word: PhantomData }
+                        spi.enable_fifos();
+                        spi.spi.spi_cr0.modify(|_, w| w.spien().set_bit());
+                        Ok(spi)
                     }
 
                     pub fn free(self) -> $SPIX {
                         self.spi
                     }
 
+                    /// The SCK frequency that was actually achieved
+                    pub fn frequency(&self, clocks: &Clocks) -> Hertz {
+                        let cp = self.spi.spi_cpr.read().cp().bits() as u32;
+                        Hertz(clocks.pclk.0 / (2 * (cp + 1)))
+                    }
+
+                    /// Lets the SPI drive the SEL pin, it gets asserted as
+                    /// soon as data is written and released once the
+                    /// transfer is complete.
+                    pub fn with_hardware_cs<SEL>(mut self, _sel: SEL, active: SelActive) -> Self
+                    where
+                        SEL: PinSel<$SPIX>
+                    {
+                        self.set_hardware_cs(Some(active));
+                        self
+                    }
+
+                    /// Changes mode and SCK frequency, e.g. to talk to
+                    /// another device on the same bus
+                    pub fn reconfigure<F>(&mut self, mode: Mode, freq: F, clocks: &Clocks) -> Result<(), config::InvalidConfig>
+                    where
+                        F: Into<Hertz>
+                    {
+                        let spi_div = spi_div(freq.into(), clocks)?;
+                        self.set_format(mode, spi_div);
+                        Ok(())
+                    }
+
+                    /// Changes the SEL and frame timing
+                    pub fn set_timing(&mut self, timing: config::SpiTiming) -> Result<(), config::InvalidConfig> {
+                        validate_timing(&timing)?;
+                        // Don't change the timing in the middle of a frame
+                        while self.spi.spi_sr.read().busy().bit_is_set() {}
+                        self.apply_timing(&timing);
+                        Ok(())
+                    }
+
+                    fn apply_timing(&mut self, timing: &config::SpiTiming) {
+                        // Refer to User Manual page 487 for the fields,
+                        // GUADT encodes guard times of 1 to 16 cycles as 0 to 15
+                        self.spi.spi_cr0.modify(|_, w| unsafe {
+                            w.selht()
+                                .bits(timing.sel_hold)
+                                .guadten()
+                                .bit(timing.guard_time.is_some())
+                                .guadt()
+                                .bits(timing.guard_time.map_or(0, |cycles| cycles - 1))
+                        });
+                    }
+
+                    fn enable_fifos(&mut self) {
+                        // Refer to User Manual page 492 for the FIFO control,
+                        // TXBE is set once the TX FIFO ran empty and RXBNE as
+                        // soon as a word was received, just like without FIFOs
+                        self.spi.spi_fcr.write(|w| unsafe {
+                            w.fifoen()
+                                .set_bit()
+                                .txftls()
+                                .bits(TX_FIFO_THRESHOLD)
+                                .rxftls()
+                                .bits(RX_FIFO_THRESHOLD)
+                        });
+                    }
+
+                    fn set_format(&mut self, mode: Mode, spi_div: u16) {
+                        // Changing the configuration in the middle of a frame
+                        // would corrupt it
+                        while self.spi.spi_sr.read().busy().bit_is_set() {}
+
+                        self.spi.spi_cr1.modify(|_, w| unsafe { w.format().bits(format_bits(mode)) });
+                        self.spi.spi_cpr.write(|w| unsafe { w.cp().bits(spi_div) });
+                    }
+
+                    fn set_hardware_cs(&mut self, active: Option<SelActive>) {
+                        match active {
+                            Some(active) => self.spi.spi_cr1.modify(|_, w| {
+                                w.selm()
+                                    // hardware SEL
+                                    .set_bit()
+                                    .selap()
+                                    .bit(active == SelActive::High)
+                            }),
+                            // SEL output stays enabled so we don't mode fault
+                            None => self.spi.spi_cr1.modify(|_, w| w.selm().clear_bit()),
+                        }
+                    }
+
                     pub fn listen(&mut self, event: Event) {
                         match event {
                             Event::ModeFault => self.spi.spi_ier.modify(|_, w| w.mfien().set_bit()),
@@ -143,7 +565,8 @@ macro_rules! spi {
                             Event::WriteCollision => self.spi.spi_ier.modify(|_, w| w.wcien().set_bit()),
                             Event::RxBufferNotEmpty => self.spi.spi_ier.modify(|_, w| w.rxbneien().set_bit()),
                             Event::TxEmpty => self.spi.spi_ier.modify(|_, w| w.txeien().set_bit()),
-                            Event::TxBufferEmpty => self.spi.spi_ier.modify(|_, w| w.txbeien().set_bit())
+                            Event::TxBufferEmpty => self.spi.spi_ier.modify(|_, w| w.txbeien().set_bit()),
+                            Event::SlaveAbort => self.spi.spi_ier.modify(|_, w| w.saien().set_bit())
                         }
                     }
                     pub fn unlisten(&mut self, event: Event) {
@@ -153,7 +576,8 @@ macro_rules! spi {
                             Event::WriteCollision => self.spi.spi_ier.modify(|_, w| w.wcien().clear_bit()),
                             Event::RxBufferNotEmpty => self.spi.spi_ier.modify(|_, w| w.rxbneien().clear_bit()),
                             Event::TxEmpty => self.spi.spi_ier.modify(|_, w| w.txeien().clear_bit()),
-                            Event::TxBufferEmpty => self.spi.spi_ier.modify(|_, w| w.txbeien().clear_bit())
+                            Event::TxBufferEmpty => self.spi.spi_ier.modify(|_, w| w.txbeien().clear_bit()),
+                            Event::SlaveAbort => self.spi.spi_ier.modify(|_, w| w.saien().clear_bit())
                         }
                     }
                 }
@@ -174,135 +598,653 @@ macro_rules! spi {
                 	MOSI: PinMosi<$SPIX>,
                         F: Into<Hertz>
                     {
-	                Spi::<$SPIX, $WORD>::$spiX(self, mode, freq, clocks)
+                        self.spi_unchecked(mode, freq, clocks)
 	            }
 
 	            fn spi_unchecked<F>(self, mode: Mode, freq: F, clocks: &Clocks) -> Spi<$SPIX, $WORD>
                     where
                         F: Into<Hertz>
                     {
-	                Spi::<$SPIX, $WORD>::$spiX(self, mode, freq, clocks)
+                        let config = config::Config::default().mode(mode).frequency(freq);
+	                Spi::<$SPIX, $WORD>::$spiX(self, config, clocks).unwrap()
 	            }
+
+                    fn spi_with_config<SCK, MISO, MOSI>(
+                        self,
+                        _sck: SCK,
+                        _miso: MISO,
+                        _mosi: MOSI,
+                        config: config::Config,
+                        clocks: &Clocks,
+                    ) -> Result<Spi<$SPIX, $WORD>, config::InvalidConfig>
+                    where
+                        SCK: PinSck<$SPIX>,
+                        MISO: PinMiso<$SPIX>,
+                        MOSI: PinMosi<$SPIX>
+                    {
+                        Spi::<$SPIX, $WORD>::$spiX(self, config, clocks)
+                    }
+
+                    fn spi_with_config_unchecked(
+                        self,
+                        config: config::Config,
+                        clocks: &Clocks,
+                    ) -> Result<Spi<$SPIX, $WORD>, config::InvalidConfig>
+                    {
+                        Spi::<$SPIX, $WORD>::$spiX(self, config, clocks)
+                    }
+
+                    fn spi_slave<SCK, MISO, MOSI, NSS>(
+                        self,
+                        _sck: SCK,
+                        _miso: MISO,
+                        _mosi: MOSI,
+                        _nss: NSS,
+                        mode: Mode
+                    ) -> SpiSlave<$SPIX, $WORD>
+                    where
+                        SCK: PinSckSlave<$SPIX>,
+                        MISO: PinMisoSlave<$SPIX>,
+                        MOSI: PinMosiSlave<$SPIX>,
+                        NSS: PinNss<$SPIX>
+                    {
+                        SpiSlave::<$SPIX, $WORD>::$spiX(self, mode)
+                    }
+
+                    fn spi_slave_unchecked(self, mode: Mode) -> SpiSlave<$SPIX, $WORD> {
+                        SpiSlave::<$SPIX, $WORD>::$spiX(self, mode)
+                    }
 	        }
 
-                impl hal::spi::FullDuplex<$WORD> for Spi<$SPIX, $WORD> {
-                    type Error = Error;
+                impl SharedSpiBus<$SPIX, $WORD> {
+                    pub fn new(spi: Spi<$SPIX, $WORD>, clocks: &Clocks) -> Self {
+                        SharedSpiBus {
+                            spi: RefCell::new(spi),
+                            clocks: *clocks,
+                            hardware_cs_taken: Cell::new(false),
+                        }
+                    }
 
-                    fn read(&mut self) -> nb::Result<$WORD, Error> {
-                        let sr = self.spi.spi_sr.read();
+                    /// Adds a device selected by a GPIO
+                    pub fn device<CS, F>(
+                        &self,
+                        cs: CS,
+                        mode: Mode,
+                        freq: F
+                    ) -> Result<SpiDevice<'_, $SPIX, $WORD, CS>, config::InvalidConfig>
+                    where
+                        CS: OutputPin<Error = Infallible>,
+                        F: Into<Hertz>
+                    {
+                        let spi_div = spi_div(freq.into(), &self.clocks)?;
+                        Ok(SpiDevice { bus: self, cs, hardware_cs: None, mode, spi_div })
+                    }
 
-                        Err(if sr.ro().bit_is_set() {
-                            nb::Error::Other(Error::Overrun)
+                    /// Adds the device selected by the hardware SEL pin.
+                    ///
+                    /// All SEL pins of an SPI carry the same signal, so only
+                    /// one such device can exist at a time, further calls
+                    /// fail with `HardwareCsTaken` until it was freed.
+                    pub fn hardware_device<SEL, F>(
+                        &self,
+                        _sel: SEL,
+                        active: SelActive,
+                        mode: Mode,
+                        freq: F
+                    ) -> Result<SpiDevice<'_, $SPIX, $WORD, HardwareCs>, config::InvalidConfig>
+                    where
+                        SEL: PinSel<$SPIX>,
+                        F: Into<Hertz>
+                    {
+                        let spi_div = spi_div(freq.into(), &self.clocks)?;
+                        if self.hardware_cs_taken.replace(true) {
+                            return Err(config::InvalidConfig::HardwareCsTaken);
                         }
-                        else if sr.wc().bit_is_set() {
-                            nb::Error::Other(Error::WriteCollision)
+                        Ok(SpiDevice {
+                            bus: self,
+                            cs: HardwareCs,
+                            hardware_cs: Some(active),
+                            mode,
+                            spi_div
+                        })
+                    }
+
+                    pub fn free(self) -> Spi<$SPIX, $WORD> {
+                        self.spi.into_inner()
+                    }
+                }
+
+                impl<'a, CS> SpiDevice<'a, $SPIX, $WORD, CS>
+                where
+                    CS: OutputPin<Error = Infallible>
+                {
+                    /// Selects the device, runs `f` on the reconfigured bus
+                    /// and deselects the device again
+                    pub fn transaction<R>(&mut self, f: impl FnOnce(&mut Spi<$SPIX, $WORD>) -> R) -> R {
+                        let mut spi = self.bus.spi.borrow_mut();
+                        spi.set_format(self.mode, self.spi_div);
+                        spi.set_hardware_cs(self.hardware_cs);
+
+                        self.cs.set_low().ok();
+                        let result = f(&mut *spi);
+                        // Wait for the last frame to leave before deselecting
+                        while spi.spi.spi_sr.read().busy().bit_is_set() {}
+                        self.cs.set_high().ok();
+
+                        result
+                    }
+
+                    pub fn free(self) -> CS {
+                        if self.hardware_cs.is_some() {
+                            self.bus.hardware_cs_taken.set(false);
                         }
-                        else if sr.rxbne().bit_is_set() {
-                            return Ok(unsafe {
-                                    ptr::read_volatile(
-                                        &self.spi.spi_dr as *const _ as *const $WORD,
-                                    )
+                        self.cs
+                    }
+                }
+
+                impl<'a, CS> hal::blocking::spi::Transfer<$WORD> for SpiDevice<'a, $SPIX, $WORD, CS>
+                where
+                    CS: OutputPin<Error = Infallible>
+                {
+                    type Error = Error;
+
+                    fn transfer<'w>(&mut self, words: &'w mut [$WORD]) -> Result<&'w [$WORD], Error> {
+                        self.transaction(|spi| {
+                            hal::blocking::spi::Transfer::transfer(spi, &mut *words).map(|_| ())
+                        })?;
+                        Ok(words)
+                    }
+                }
+
+                impl<'a, CS> hal::blocking::spi::Write<$WORD> for SpiDevice<'a, $SPIX, $WORD, CS>
+                where
+                    CS: OutputPin<Error = Infallible>
+                {
+                    type Error = Error;
+
+                    fn write(&mut self, words: &[$WORD]) -> Result<(), Error> {
+                        self.transaction(|spi| hal::blocking::spi::Write::write(spi, words))
+                    }
+                }
+
+                impl<'a, CS> hal1::spi::SpiDevice<$WORD> for SpiDevice<'a, $SPIX, $WORD, CS>
+                where
+                    CS: OutputPin<Error = Infallible>
+                {
+                    fn transaction(&mut self, operations: &mut [hal1::spi::Operation<'_, $WORD>]) -> Result<(), Error> {
+                        use hal1::spi::{Operation, SpiBus};
+
+                        // The delay counts core cycles, unlike SCK which is
+                        // derived from PCLK
+                        let hclk = self.bus.clocks.hclk.0 as u64;
+                        Self::transaction(self, |spi| {
+                            for operation in operations {
+                                match operation {
+                                    Operation::Read(words) => SpiBus::read(spi, words)?,
+                                    Operation::Write(words) => SpiBus::write(spi, words)?,
+                                    Operation::Transfer(read, write) => SpiBus::transfer(spi, read, write)?,
+                                    Operation::TransferInPlace(words) => SpiBus::transfer_in_place(spi, words)?,
+                                    Operation::DelayNs(ns) => {
+                                        SpiBus::flush(spi)?;
+                                        // rounded up so we rather wait longer
+                                        let cycles = (hclk * *ns as u64).div_ceil(1_000_000_000);
+                                        cortex_m::asm::delay(cycles as u32);
+                                    }
                                 }
-                            )
+                            }
+                            Ok(())
+                        })
+                    }
+                }
+
+                impl SpiSlave<$SPIX, $WORD> {
+                    fn $spiX(spi: $SPIX, mode: Mode) -> SpiSlave<$SPIX, $WORD> {
+                        // reset the SPI port before using it
+                        $SPIX::reset();
+                        // enable the APB clock for the SPI port
+                        $SPIX::enable();
+
+                        let mode = format_bits(mode);
+
+                        spi.spi_cr1.modify(|_, w| unsafe {
+                            w.mode().
+                                // slave mode
+                                clear_bit().
+                                selm().
+                                // SEL is handled by hardware, transfers
+                                // only happen while the master asserts it
+                                set_bit().
+                                firstbit().
+                                // MSB first
+                                clear_bit().
+                                format().
+                                bits(mode).
+                                dfl().
+                                // data frame length, 16 bits are encoded as 0
+                                bits(((core::mem::size_of::<$WORD>() * 8) & 0xf) as u8)
+                        });
+
+                        spi.spi_cr0.modify(|_, w| w.spien().set_bit());
+                        SpiSlave { spi, _word: PhantomData }
+                    }
+
+                    pub fn free(self) -> $SPIX {
+                        self.spi
+                    }
+
+                    /// Whether a frame is currently being clocked by the master
+                    pub fn is_busy(&self) -> bool {
+                        self.spi.spi_sr.read().busy().bit_is_set()
+                    }
+
+                    /// Clears all error flags, e.g. from an interrupt handler
+                    /// after a `SlaveAbort`
+                    pub fn clear_errors(&mut self) {
+                        // write 1 to clear
+                        self.spi.spi_sr.write(|w| w.ro().set_bit().wc().set_bit().mf().set_bit().sa().set_bit());
+                    }
+
+                    pub fn listen(&mut self, event: Event) {
+                        match event {
+                            Event::ModeFault => self.spi.spi_ier.modify(|_, w| w.mfien().set_bit()),
+                            Event::ReadOverrun => self.spi.spi_ier.modify(|_, w| w.roien().set_bit()),
+                            Event::WriteCollision => self.spi.spi_ier.modify(|_, w| w.wcien().set_bit()),
+                            Event::RxBufferNotEmpty => self.spi.spi_ier.modify(|_, w| w.rxbneien().set_bit()),
+                            Event::TxEmpty => self.spi.spi_ier.modify(|_, w| w.txeien().set_bit()),
+                            Event::TxBufferEmpty => self.spi.spi_ier.modify(|_, w| w.txbeien().set_bit()),
+                            Event::SlaveAbort => self.spi.spi_ier.modify(|_, w| w.saien().set_bit())
+                        }
+                    }
+
+                    pub fn unlisten(&mut self, event: Event) {
+                        match event {
+                            Event::ModeFault => self.spi.spi_ier.modify(|_, w| w.mfien().clear_bit()),
+                            Event::ReadOverrun => self.spi.spi_ier.modify(|_, w| w.roien().clear_bit()),
+                            Event::WriteCollision => self.spi.spi_ier.modify(|_, w| w.wcien().clear_bit()),
+                            Event::RxBufferNotEmpty => self.spi.spi_ier.modify(|_, w| w.rxbneien().clear_bit()),
+                            Event::TxEmpty => self.spi.spi_ier.modify(|_, w| w.txeien().clear_bit()),
+                            Event::TxBufferEmpty => self.spi.spi_ier.modify(|_, w| w.txbeien().clear_bit()),
+                            Event::SlaveAbort => self.spi.spi_ier.modify(|_, w| w.saien().clear_bit())
+                        }
+                    }
+                }
+
+                /// `send` preloads the word that is shifted out during the
+                /// next frame the master clocks, `read` returns the word the
+                /// master sent during the last one.
+                impl hal::spi::FullDuplex<$WORD> for SpiSlave<$SPIX, $WORD> {
+                    type Error = Error;
+
+                    fn read(&mut self) -> nb::Result<$WORD, Error> {
+                        let sr = check_errors!(self.spi).map_err(nb::Error::Other)?;
+
+                        if sr.rxbne().bit_is_set() {
+                            Ok(unsafe {
+                                ptr::read_volatile(&self.spi.spi_dr as *const _ as *const $WORD)
+                            })
                         }
                         else {
-                            nb::Error::WouldBlock
-                        })
+                            Err(nb::Error::WouldBlock)
+                        }
                     }
 
-                    fn send(&mut self, byte: $WORD) -> nb::Result<(), Error> {
-                        let sr = self.spi.spi_sr.read();
+                    fn send(&mut self, word: $WORD) -> nb::Result<(), Error> {
+                        let sr = check_errors!(self.spi).map_err(nb::Error::Other)?;
 
-                        Err(if sr.ro().bit_is_set() {
-                            nb::Error::Other(Error::Overrun)
+                        if sr.txbe().bit_is_set() {
+                            unsafe {
+                                ptr::write_volatile(
+                                    ptr::addr_of!(self.spi.spi_dr) as *mut $WORD,
+                                    word,
+                                )
+                            }
+                            Ok(())
                         }
-                        else if sr.wc().bit_is_set() {
-                            nb::Error::Other(Error::WriteCollision)
+                        else {
+                            Err(nb::Error::WouldBlock)
                         }
-                        else if sr.busy().bit_is_set() {
-                            nb::Error::WouldBlock
+                    }
+                }
+
+                impl hal::spi::FullDuplex<$WORD> for Spi<$SPIX, $WORD> {
+                    type Error = Error;
+
+                    fn read(&mut self) -> nb::Result<$WORD, Error> {
+                        let sr = check_errors!(self.spi).map_err(nb::Error::Other)?;
+
+                        if sr.rxbne().bit_is_set() {
+                            Ok(unsafe {
+                                ptr::read_volatile(&self.spi.spi_dr as *const _ as *const $WORD)
+                            })
                         }
                         else {
+                            Err(nb::Error::WouldBlock)
+                        }
+                    }
+
+                    fn send(&mut self, byte: $WORD) -> nb::Result<(), Error> {
+                        let sr = check_errors!(self.spi).map_err(nb::Error::Other)?;
+
+                        if sr.txbe().bit_is_set() {
                             unsafe {
                                 ptr::write_volatile(
-                                    &self.spi.spi_dr as *const _ as *mut $WORD,
+                                    ptr::addr_of!(self.spi.spi_dr) as *mut $WORD,
                                     byte,
                                 )
                             }
-                            return Ok(());
+                            Ok(())
+                        }
+                        else {
+                            Err(nb::Error::WouldBlock)
+                        }
+                    }
+                }
+
+                impl Spi<$SPIX, $WORD> {
+                    /// Brings the SPI back into a usable state after an
+                    /// error, words that are still in the FIFOs are lost.
+                    pub fn recover(&mut self) {
+                        // Disabling the SPI aborts the current frame and
+                        // flushes the FIFOs
+                        self.spi.spi_cr0.modify(|_, w| w.spien().clear_bit());
+                        while self.spi.spi_sr.read().rxbne().bit_is_set() {
+                            unsafe {
+                                ptr::read_volatile(&self.spi.spi_dr as *const _ as *const $WORD);
+                            }
+                        }
+                        // write 1 to clear
+                        self.spi.spi_sr.write(|w| w.ro().set_bit().wc().set_bit().mf().set_bit().sa().set_bit());
+                        // A mode fault drops the SPI out of master mode
+                        self.spi.spi_cr1.modify(|_, w| w.mode().set_bit());
+                        self.spi.spi_cr0.modify(|_, w| w.spien().set_bit());
+                    }
+
+                    /// Sends `words` and replaces them with the received ones
+                    pub fn transfer_in_place(&mut self, words: &mut [$WORD]) -> Result<(), Error> {
+                        let cells = Cell::from_mut(words).as_slice_of_cells();
+                        let mut rx = cells.iter();
+                        self.exchange(cells.iter().map(Cell::get), |word| {
+                            if let Some(cell) = rx.next() {
+                                cell.set(word)
+                            }
+                        })
+                    }
+
+                    /// Keeps the TX FIFO filled from `words` and hands all
+                    /// received words to `rx`.
+                    ///
+                    /// At most `FIFO_DEPTH` words are in flight at any time,
+                    /// this way the RX FIFO can't overrun even if we get
+                    /// interrupted.
+                    fn exchange<I, R>(&mut self, mut words: I, mut rx: R) -> Result<(), Error>
+                    where
+                        I: Iterator<Item = $WORD>,
+                        R: FnMut($WORD)
+                    {
+                        let mut in_flight = 0;
+                        let mut next = words.next();
+                        loop {
+                            if let Err(e) = check_errors!(self.spi) {
+                                break Err(e);
+                            }
+
+                            if let Some(word) = next {
+                                if in_flight < FIFO_DEPTH {
+                                    unsafe {
+                                        ptr::write_volatile(
+                                            ptr::addr_of!(self.spi.spi_dr) as *mut $WORD,
+                                            word,
+                                        )
+                                    }
+                                    in_flight += 1;
+                                    next = words.next();
+                                }
+                            }
+
+                            if self.spi.spi_fsr.read().rxfs().bits() > 0 {
+                                rx(unsafe {
+                                    ptr::read_volatile(&self.spi.spi_dr as *const _ as *const $WORD)
+                                });
+                                in_flight -= 1;
+                            } else if next.is_none() && in_flight == 0 {
+                                break Ok(());
+                            }
+                        }
+                    }
+                }
+
+                impl Spi<$SPIX, $WORD> {
+                    /// Sends `buffer` via the PDMA, received words are
+                    /// discarded
+                    pub fn write_dma<B, TX>(self, buffer: B, mut tx: TX) -> Transfer<B, TX, Self>
+                    where
+                        B: ReadBuffer<Word = $WORD>,
+                        TX: TxChannel<$SPIX>
+                    {
+                        let (ptr, len) = unsafe { buffer.read_buffer() };
+                        assert!(len <= u16::MAX as usize);
+
+                        unsafe {
+                            tx.configure(
+                                ptr as u32,
+                                true,
+                                &self.spi.spi_dr as *const _ as u32,
+                                false,
+                                <$WORD as pdma::Word>::WIDTH,
+                                len as u16,
+                            );
+                        }
+                        compiler_fence(Ordering::Release);
+                        tx.start();
+                        // Refer to User Manual page 489 for the DMA requests
+                        self.spi.spi_cr0.modify(|_, w| w.txdmae().set_bit());
+
+                        Transfer::new(buffer, tx, self)
+                    }
+
+                    /// Sends `tx_buffer` via the PDMA while the received
+                    /// words are stored in `rx_buffer`, both need to have the
+                    /// same length
+                    pub fn transfer_dma<TB, RB, RX, TX>(
+                        self,
+                        tx_buffer: TB,
+                        mut rx_buffer: RB,
+                        mut rx: RX,
+                        mut tx: TX,
+                    ) -> Transfer<(TB, RB), (RX, TX), Self>
+                    where
+                        TB: ReadBuffer<Word = $WORD>,
+                        RB: WriteBuffer<Word = $WORD>,
+                        RX: RxChannel<$SPIX>,
+                        TX: TxChannel<$SPIX>
+                    {
+                        let (tx_ptr, tx_len) = unsafe { tx_buffer.read_buffer() };
+                        let (rx_ptr, rx_len) = unsafe { rx_buffer.write_buffer() };
+                        assert_eq!(tx_len, rx_len);
+                        assert!(tx_len <= u16::MAX as usize);
+
+                        let dr = &self.spi.spi_dr as *const _ as u32;
+                        unsafe {
+                            rx.configure(dr, false, rx_ptr as u32, true, <$WORD as pdma::Word>::WIDTH, rx_len as u16);
+                            tx.configure(tx_ptr as u32, true, dr, false, <$WORD as pdma::Word>::WIDTH, tx_len as u16);
+                        }
+                        compiler_fence(Ordering::Release);
+                        // RX has to be ready before the first word goes out
+                        rx.start();
+                        tx.start();
+                        self.spi.spi_cr0.modify(|_, w| w.rxdmae().set_bit().txdmae().set_bit());
+
+                        Transfer::new((tx_buffer, rx_buffer), (rx, tx), self)
+                    }
+                }
+
+                impl TransferPayload for Spi<$SPIX, $WORD> {
+                    type Error = Error;
+
+                    fn check(&mut self) -> Result<(), Error> {
+                        // Without an RX channel nobody collects the received
+                        // words, so they overrun on purpose, `stop` clears
+                        // the flag once the transfer is over
+                        if self.spi.spi_cr0.read().rxdmae().bit_is_clear() {
+                            let sr = self.spi.spi_sr.read();
+                            return if sr.mf().bit_is_set() {
+                                self.spi.spi_sr.write(|w| w.mf().set_bit());
+                                Err(Error::ModeFault)
+                            } else if sr.sa().bit_is_set() {
+                                self.spi.spi_sr.write(|w| w.sa().set_bit());
+                                Err(Error::SlaveAbort)
+                            } else if sr.wc().bit_is_set() {
+                                self.spi.spi_sr.write(|w| w.wc().set_bit());
+                                Err(Error::WriteCollision)
+                            } else {
+                                Ok(())
+                            };
+                        }
+                        check_errors!(self.spi).map(|_| ())
+                    }
+
+                    fn stop(&mut self) {
+                        // The PDMA is done once the last word was written to
+                        // the SPI, wait until it left
+                        loop {
+                            let sr = self.spi.spi_sr.read();
+                            if sr.txe().bit_is_set() && sr.busy().bit_is_clear() {
+                                break;
+                            }
+                        }
+                        self.spi.spi_cr0.modify(|_, w| w.rxdmae().clear_bit().txdmae().clear_bit());
+
+                        while self.spi.spi_sr.read().rxbne().bit_is_set() {
+                            unsafe {
+                                ptr::read_volatile(&self.spi.spi_dr as *const _ as *const $WORD);
+                            }
+                        }
+                        // write 1 to clear
+                        self.spi.spi_sr.write(|w| w.ro().set_bit());
+                    }
+                }
+
+                impl hal::blocking::spi::Transfer<$WORD> for Spi<$SPIX, $WORD> {
+                    type Error = Error;
+
+                    fn transfer<'w>(&mut self, words: &'w mut [$WORD]) -> Result<&'w [$WORD], Error> {
+                        self.transfer_in_place(words)?;
+                        Ok(words)
+                    }
+                }
+
+                impl hal::blocking::spi::Write<$WORD> for Spi<$SPIX, $WORD> {
+                    type Error = Error;
+
+                    /// Received words are discarded
+                    fn write(&mut self, words: &[$WORD]) -> Result<(), Error> {
+                        self.exchange(words.iter().copied(), |_| {})
+                    }
+                }
+
+                impl hal1::spi::SpiBus<$WORD> for Spi<$SPIX, $WORD> {
+                    fn read(&mut self, words: &mut [$WORD]) -> Result<(), Error> {
+                        let len = words.len();
+                        let mut rx = words.iter_mut();
+                        self.exchange(core::iter::repeat(0).take(len), |word| {
+                            if let Some(slot) = rx.next() {
+                                *slot = word
+                            }
                         })
                     }
+
+                    fn write(&mut self, words: &[$WORD]) -> Result<(), Error> {
+                        self.exchange(words.iter().copied(), |_| {})
+                    }
+
+                    /// Zeros are sent once `write` is exhausted, words that
+                    /// don't fit into `read` are discarded
+                    fn transfer(&mut self, read: &mut [$WORD], write: &[$WORD]) -> Result<(), Error> {
+                        let len = read.len().max(write.len());
+                        let mut rx = read.iter_mut();
+                        let tx = write.iter().copied().chain(core::iter::repeat(0)).take(len);
+                        self.exchange(tx, |word| {
+                            if let Some(slot) = rx.next() {
+                                *slot = word
+                            }
+                        })
+                    }
+
+                    fn transfer_in_place(&mut self, words: &mut [$WORD]) -> Result<(), Error> {
+                        Self::transfer_in_place(self, words)
+                    }
+
+                    fn flush(&mut self) -> Result<(), Error> {
+                        while self.spi.spi_sr.read().busy().bit_is_set() {}
+                        Ok(())
+                    }
                 }
 
-                impl hal::blocking::spi::transfer::Default<$WORD>
-                    for Spi<$SPIX, $WORD> {}
+                #[cfg(feature = "unproven")]
+                impl hal::blocking::spi::WriteIter<$WORD> for Spi<$SPIX, $WORD> {
+                    type Error = Error;
 
-                impl hal::blocking::spi::write::Default<$WORD>
-                    for Spi<$SPIX, $WORD> {}
+                    /// Received words are discarded
+                    fn write_iter<WI>(&mut self, words: WI) -> Result<(), Error>
+                    where
+                        WI: IntoIterator<Item = $WORD>
+                    {
+                        self.exchange(words.into_iter(), |_| {})
+                    }
+                }
             )+
         )+
     }
 }
 
+// Expanded by the table in `instance`, the pins, their modes and the
+// alternate functions are resolved there
 macro_rules! pins {
-    ($($SPIX:ty: SCK: [$($SCK:ty),*] MISO: [$($MISO:ty),*] MOSI: [$($MOSI:ty),*])+) => {
+    (
+        $SPIX:ty:
+        SCK: [$($SCK:ident),*],
+        MISO: [$($MISO:ident),*],
+        MOSI: [$($MOSI:ident),*],
+        SEL: [$($SEL:ident),*],
+    ) => {
         $(
-            $(
-                impl PinSck<$SPIX> for $SCK {}
-            )*
-            $(
-                impl PinMiso<$SPIX> for $MISO {}
-            )*
-            $(
-                impl PinMosi<$SPIX> for $MOSI {}
-            )*
+            impl $crate::spi::PinSck<$SPIX> for $SCK<Output<PushPull>, AF5> {}
+            impl $crate::spi::PinSckSlave<$SPIX> for $SCK<Input<Floating>, AF5> {}
+        )*
+        $(
+            impl $crate::spi::PinMiso<$SPIX> for $MISO<Input<Floating>, AF5> {}
+            impl $crate::spi::PinMisoSlave<$SPIX> for $MISO<Output<PushPull>, AF5> {}
+        )*
+        $(
+            impl $crate::spi::PinMosi<$SPIX> for $MOSI<Output<PushPull>, AF5> {}
+            impl $crate::spi::PinMosiSlave<$SPIX> for $MOSI<Input<Floating>, AF5> {}
+        )*
+        $(
+            impl $crate::spi::PinSel<$SPIX> for $SEL<Output<PushPull>, AF5> {}
+            impl $crate::spi::PinNss<$SPIX> for $SEL<Input<Floating>, AF5> {}
+        )*
+    }
+}
+
+pub(crate) use pins;
+
+macro_rules! dma_channels {
+    ($($SPIX:ty: RX: $RX:ty, TX: $TX:ty)+) => {
+        $(
+            impl RxChannel<$SPIX> for $RX {}
+            impl TxChannel<$SPIX> for $TX {}
         )+
     }
 }
 
 spi! {
-    SPI0: (spi0, spi0en, spi0rst) => (u8, u16),
-    SPI1: (spi1, spi1en, spi1rst) => (u8, u16),
+    SPI0: (spi0) => (u8, u16),
+    SPI1: (spi1) => (u8, u16),
 }
 
-pins! {
-    SPI0:
-        SCK: [
-            PA4<Output<PushPull>, AF5>,
-            PC0<Output<PushPull>, AF5>,
-            PB3<Output<PushPull>, AF5>
-        ]
-        MISO: [
-            PA6<Input<Floating>, AF5>,
-            PA11<Input<Floating>, AF5>,
-            PB5<Input<Floating>, AF5>
-        ]
-        MOSI: [
-            PA5<Output<PushPull>, AF5>,
-            PA9<Output<PushPull>, AF5>,
-            PB4<Output<PushPull>, AF5>
-        ]
-    SPI1:
-        SCK: [
-            PA0<Output<PushPull>, AF5>,
-            PC5<Output<PushPull>, AF5>,
-            PC11<Output<PushPull>, AF5>,
-            PA15<Output<PushPull>, AF5>,
-            PC2<Output<PushPull>, AF5>
-        ]
-        MISO: [
-            PA2<Input<Floating>, AF5>,
-            PC9<Input<Floating>, AF5>,
-            PC13<Input<Floating>, AF5>,
-            PB1<Input<Floating>, AF5>,
-            PB6<Input<Floating>, AF5>
-        ]
-        MOSI: [
-            PA1<Output<PushPull>, AF5>,
-            PC8<Output<PushPull>, AF5>,
-            PC12<Output<PushPull>, AF5>,
-            PB0<Output<PushPull>, AF5>,
-            PC3<Output<PushPull>, AF5>
-        ]
-
+// Refer to User Manual page 231 for the request mapping, several
+// peripherals share a channel, e.g. SPI1 and I2C1
+dma_channels! {
+    SPI0: RX: pdma::Ch0, TX: pdma::Ch1
+    SPI1: RX: pdma::Ch2, TX: pdma::Ch3
 }
@@ -0,0 +1,557 @@
+//! Serial NOR flash (W25Q and compatible) on top of an SPI bus
+//!
+//! `SpiFlash` talks to a real chip, `FakeFlash` keeps the contents in RAM
+//! and follows the same NOR rules, so code written against the `Flash`
+//! trait can be exercised without hardware.
+use crate::hal::blocking::spi::{Transfer, Write};
+use crate::hal::digital::v2::OutputPin;
+use core::convert::Infallible;
+
+/// Size of a page, the unit of a single program command
+pub const PAGE_SIZE: u32 = 256;
+/// Size of a sector, the smallest erasable unit
+pub const SECTOR_SIZE: u32 = 4096;
+/// Size of a block
+pub const BLOCK_SIZE: u32 = 65536;
+
+/// The chips are addressed with 24 bits
+const ADDRESS_LIMIT: u32 = 1 << 24;
+
+mod command {
+    pub const WRITE_ENABLE: u8 = 0x06;
+    pub const READ_STATUS: u8 = 0x05;
+    pub const PAGE_PROGRAM: u8 = 0x02;
+    pub const FAST_READ: u8 = 0x0b;
+    pub const SECTOR_ERASE: u8 = 0x20;
+    pub const BLOCK_ERASE: u8 = 0xd8;
+    pub const CHIP_ERASE: u8 = 0xc7;
+    pub const JEDEC_ID: u8 = 0x9f;
+}
+
+/// Bits of status register 1
+const STATUS_BUSY: u8 = 1 << 0;
+
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The underlying SPI failed
+    Spi(E),
+    /// The access does not fit into the address space of the chip
+    OutOfBounds,
+    /// An erase address is not aligned to the erase size
+    Unaligned,
+    /// A single page program would cross a page boundary
+    PageOverflow,
+}
+
+/// Manufacturer and device identification as returned by command 0x9F
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct JedecId {
+    pub manufacturer: u8,
+    pub memory_type: u8,
+    /// The capacity as a power of two
+    pub capacity: u8,
+}
+
+impl JedecId {
+    pub fn capacity_bytes(&self) -> u32 {
+        1 << self.capacity
+    }
+}
+
+/// Common operations of serial NOR flashes, bits can only be programmed
+/// from 1 to 0, only an erase sets them back to 1.
+pub trait Flash {
+    type Error;
+
+    fn jedec_id(&mut self) -> Result<JedecId, Self::Error>;
+
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Programs `data`, it may span several pages
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Erases the sector starting at `address`
+    fn erase_sector(&mut self, address: u32) -> Result<(), Self::Error>;
+
+    /// Erases the block starting at `address`
+    fn erase_block(&mut self, address: u32) -> Result<(), Self::Error>;
+
+    fn erase_chip(&mut self) -> Result<(), Self::Error>;
+}
+
+/// A flash chip with its own chip select
+#[derive(Debug)]
+pub struct SpiFlash<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+}
+
+impl<SPI, CS, E> SpiFlash<SPI, CS>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    CS: OutputPin<Error = Infallible>,
+{
+    pub fn new(spi: SPI, mut cs: CS) -> Self {
+        cs.set_high().ok();
+        SpiFlash { spi, cs }
+    }
+
+    pub fn free(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+
+    /// Reads status register 1
+    pub fn read_status(&mut self) -> Result<u8, Error<E>> {
+        let mut status = [command::READ_STATUS, 0];
+        self.transaction(|spi| spi.transfer(&mut status).map(|_| ()))?;
+        Ok(status[1])
+    }
+
+    /// Whether a program or erase operation is still running
+    pub fn is_busy(&mut self) -> Result<bool, Error<E>> {
+        Ok(self.read_status()? & STATUS_BUSY != 0)
+    }
+
+    /// Polls the status until the last program or erase operation is done
+    pub fn wait_ready(&mut self) -> Result<(), Error<E>> {
+        while self.is_busy()? {}
+        Ok(())
+    }
+
+    /// Programs up to one page, `data` must not cross a page boundary as
+    /// the chip would wrap around to the start of the page
+    pub fn page_program(&mut self, address: u32, data: &[u8]) -> Result<(), Error<E>> {
+        check_bounds(address, data.len())?;
+        if address % PAGE_SIZE + data.len() as u32 > PAGE_SIZE {
+            return Err(Error::PageOverflow);
+        }
+        self.write_enable()?;
+        let header = header(command::PAGE_PROGRAM, address);
+        self.transaction(|spi| {
+            spi.write(&header)?;
+            spi.write(data)
+        })?;
+        self.wait_ready()
+    }
+
+    /// Reads with the fast read command, which works at the maximum SCK
+    /// frequency of the chip
+    pub fn fast_read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), Error<E>> {
+        check_bounds(address, buffer.len())?;
+        let header = header(command::FAST_READ, address);
+        self.transaction(|spi| {
+            spi.write(&header)?;
+            // one dummy byte
+            spi.write(&[0])?;
+            spi.transfer(buffer).map(|_| ())
+        })
+    }
+
+    fn write_enable(&mut self) -> Result<(), Error<E>> {
+        self.transaction(|spi| spi.write(&[command::WRITE_ENABLE]))
+    }
+
+    fn erase(&mut self, command: u8, address: u32, size: u32) -> Result<(), Error<E>> {
+        if !address.is_multiple_of(size) {
+            return Err(Error::Unaligned);
+        }
+        check_bounds(address, size as usize)?;
+        self.write_enable()?;
+        let header = header(command, address);
+        self.transaction(|spi| spi.write(&header))?;
+        self.wait_ready()
+    }
+
+    /// Runs `f` with the chip selected
+    fn transaction<R>(&mut self, f: impl FnOnce(&mut SPI) -> Result<R, E>) -> Result<R, Error<E>> {
+        self.cs.set_low().ok();
+        let result = f(&mut self.spi);
+        self.cs.set_high().ok();
+        result.map_err(Error::Spi)
+    }
+}
+
+impl<SPI, CS, E> Flash for SpiFlash<SPI, CS>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    CS: OutputPin<Error = Infallible>,
+{
+    type Error = Error<E>;
+
+    fn jedec_id(&mut self) -> Result<JedecId, Error<E>> {
+        let mut id = [command::JEDEC_ID, 0, 0, 0];
+        self.transaction(|spi| spi.transfer(&mut id).map(|_| ()))?;
+        Ok(JedecId {
+            manufacturer: id[1],
+            memory_type: id[2],
+            capacity: id[3],
+        })
+    }
+
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), Error<E>> {
+        self.fast_read(address, buffer)
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), Error<E>> {
+        check_bounds(address, data.len())?;
+        for_each_page(address, data, |address, chunk| self.page_program(address, chunk))
+    }
+
+    fn erase_sector(&mut self, address: u32) -> Result<(), Error<E>> {
+        self.erase(command::SECTOR_ERASE, address, SECTOR_SIZE)
+    }
+
+    fn erase_block(&mut self, address: u32) -> Result<(), Error<E>> {
+        self.erase(command::BLOCK_ERASE, address, BLOCK_SIZE)
+    }
+
+    fn erase_chip(&mut self) -> Result<(), Error<E>> {
+        self.write_enable()?;
+        self.transaction(|spi| spi.write(&[command::CHIP_ERASE]))?;
+        self.wait_ready()
+    }
+}
+
+/// A flash that lives in RAM, e.g. to test code using a `Flash` on the host
+#[derive(Debug)]
+pub struct FakeFlash<'a> {
+    memory: &'a mut [u8],
+    id: JedecId,
+}
+
+impl<'a> FakeFlash<'a> {
+    /// Uses `memory` as flash contents, it is not erased. Its length should
+    /// be a multiple of `BLOCK_SIZE` to allow erasing all of it by block.
+    pub fn new(memory: &'a mut [u8], id: JedecId) -> Self {
+        FakeFlash { memory, id }
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        self.memory
+    }
+
+    fn range(&self, address: u32, len: usize) -> Result<core::ops::Range<usize>, Error<Infallible>> {
+        let start = address as usize;
+        match start.checked_add(len) {
+            Some(end) if end <= self.memory.len() => Ok(start..end),
+            _ => Err(Error::OutOfBounds),
+        }
+    }
+
+    fn erase(&mut self, address: u32, size: u32) -> Result<(), Error<Infallible>> {
+        if !address.is_multiple_of(size) {
+            return Err(Error::Unaligned);
+        }
+        let range = self.range(address, size as usize)?;
+        for byte in &mut self.memory[range] {
+            *byte = 0xff;
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Flash for FakeFlash<'a> {
+    type Error = Error<Infallible>;
+
+    fn jedec_id(&mut self) -> Result<JedecId, Error<Infallible>> {
+        Ok(self.id)
+    }
+
+    fn read(&mut self, address: u32, buffer: &mut [u8]) -> Result<(), Error<Infallible>> {
+        let range = self.range(address, buffer.len())?;
+        buffer.copy_from_slice(&self.memory[range]);
+        Ok(())
+    }
+
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), Error<Infallible>> {
+        let range = self.range(address, data.len())?;
+        for (byte, new) in self.memory[range].iter_mut().zip(data) {
+            // programming can only clear bits
+            *byte &= *new;
+        }
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, address: u32) -> Result<(), Error<Infallible>> {
+        self.erase(address, SECTOR_SIZE)
+    }
+
+    fn erase_block(&mut self, address: u32) -> Result<(), Error<Infallible>> {
+        self.erase(address, BLOCK_SIZE)
+    }
+
+    fn erase_chip(&mut self) -> Result<(), Error<Infallible>> {
+        for byte in self.memory.iter_mut() {
+            *byte = 0xff;
+        }
+        Ok(())
+    }
+}
+
+/// A command byte followed by a 24 bit address, MSB first
+fn header(command: u8, address: u32) -> [u8; 4] {
+    [command, (address >> 16) as u8, (address >> 8) as u8, address as u8]
+}
+
+fn check_bounds<E>(address: u32, len: usize) -> Result<(), Error<E>> {
+    if address as u64 + len as u64 > ADDRESS_LIMIT as u64 {
+        return Err(Error::OutOfBounds);
+    }
+    Ok(())
+}
+
+/// Splits `data` at page boundaries
+fn for_each_page<E>(
+    mut address: u32,
+    mut data: &[u8],
+    mut f: impl FnMut(u32, &[u8]) -> Result<(), E>,
+) -> Result<(), E> {
+    while !data.is_empty() {
+        let space = (PAGE_SIZE - address % PAGE_SIZE) as usize;
+        let (chunk, rest) = data.split_at(space.min(data.len()));
+        f(address, chunk)?;
+        address += chunk.len() as u32;
+        data = rest;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+
+    const ID: JedecId = JedecId {
+        manufacturer: 0xef,
+        memory_type: 0x40,
+        capacity: 17,
+    };
+
+    /// A chip on the other end of the SPI, it decodes the commands once
+    /// CS goes high again and applies them to a `FakeFlash`
+    struct Chip<'a> {
+        flash: FakeFlash<'a>,
+        selected: bool,
+        received: Vec<u8>,
+        write_enabled: bool,
+    }
+
+    impl<'a> Chip<'a> {
+        /// The byte the chip shifts out while receiving the `n`th byte of
+        /// the current command
+        fn reply(&mut self, n: usize) -> u8 {
+            match self.received[0] {
+                command::JEDEC_ID => [0, ID.manufacturer, ID.memory_type, ID.capacity][n.min(3)],
+                command::READ_STATUS => 0,
+                // command, address and dummy byte
+                command::FAST_READ if n >= 5 => {
+                    let address = address(&self.received) + (n - 5) as u32;
+                    self.flash.memory()[address as usize]
+                }
+                _ => 0xff,
+            }
+        }
+
+        fn execute(&mut self) {
+            let command = self.received[0];
+            match command {
+                command::WRITE_ENABLE => self.write_enabled = true,
+                command::PAGE_PROGRAM | command::SECTOR_ERASE | command::BLOCK_ERASE | command::CHIP_ERASE => {
+                    assert!(self.write_enabled);
+                    self.write_enabled = false;
+                    let address = address(&self.received);
+                    match command {
+                        command::PAGE_PROGRAM => {
+                            // The address wraps around within the page
+                            let page = address - address % PAGE_SIZE;
+                            for (i, byte) in self.received[4..].iter().enumerate() {
+                                let offset = (address % PAGE_SIZE + i as u32) % PAGE_SIZE;
+                                self.flash.write(page + offset, &[*byte]).unwrap();
+                            }
+                        }
+                        command::SECTOR_ERASE => self.flash.erase_sector(address).unwrap(),
+                        command::BLOCK_ERASE => self.flash.erase_block(address).unwrap(),
+                        _ => self.flash.erase_chip().unwrap(),
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn address(received: &[u8]) -> u32 {
+        (received[1] as u32) << 16 | (received[2] as u32) << 8 | received[3] as u32
+    }
+
+    struct FakeSpi<'a, 'b>(&'b RefCell<Chip<'a>>);
+
+    impl<'a, 'b> Transfer<u8> for FakeSpi<'a, 'b> {
+        type Error = Infallible;
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+            let mut chip = self.0.borrow_mut();
+            assert!(chip.selected);
+            for word in words.iter_mut() {
+                chip.received.push(*word);
+                let n = chip.received.len() - 1;
+                *word = chip.reply(n);
+            }
+            Ok(words)
+        }
+    }
+
+    impl<'a, 'b> Write<u8> for FakeSpi<'a, 'b> {
+        type Error = Infallible;
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+            let mut chip = self.0.borrow_mut();
+            assert!(chip.selected);
+            chip.received.extend_from_slice(words);
+            Ok(())
+        }
+    }
+
+    struct FakeCs<'a, 'b>(&'b RefCell<Chip<'a>>);
+
+    impl<'a, 'b> OutputPin for FakeCs<'a, 'b> {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            let mut chip = self.0.borrow_mut();
+            chip.selected = true;
+            chip.received.clear();
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            let mut chip = self.0.borrow_mut();
+            if chip.selected && !chip.received.is_empty() {
+                chip.execute();
+            }
+            chip.selected = false;
+            Ok(())
+        }
+    }
+
+    fn chip(memory: &mut [u8]) -> RefCell<Chip<'_>> {
+        RefCell::new(Chip {
+            flash: FakeFlash::new(memory, ID),
+            selected: false,
+            received: Vec::new(),
+            write_enabled: false,
+        })
+    }
+
+    #[test]
+    fn fake_jedec_id() {
+        let mut memory = [0xff; 2 * BLOCK_SIZE as usize];
+        let mut flash = FakeFlash::new(&mut memory, ID);
+        assert_eq!(flash.jedec_id().unwrap(), ID);
+        assert_eq!(ID.capacity_bytes(), 2 * BLOCK_SIZE);
+    }
+
+    #[test]
+    fn fake_program_only_clears_bits() {
+        let mut memory = [0xff; BLOCK_SIZE as usize];
+        let mut flash = FakeFlash::new(&mut memory, ID);
+        flash.write(10, &[0xf0, 0x0f]).unwrap();
+        flash.write(10, &[0x3c, 0xff]).unwrap();
+        let mut buffer = [0; 2];
+        flash.read(10, &mut buffer).unwrap();
+        assert_eq!(buffer, [0x30, 0x0f]);
+    }
+
+    #[test]
+    fn fake_erase() {
+        let mut memory = [0; BLOCK_SIZE as usize];
+        let mut flash = FakeFlash::new(&mut memory, ID);
+        assert!(matches!(flash.erase_sector(1), Err(Error::Unaligned)));
+        flash.erase_sector(SECTOR_SIZE).unwrap();
+        assert!(flash.memory()[..SECTOR_SIZE as usize].iter().all(|b| *b == 0));
+        assert!(flash.memory()[SECTOR_SIZE as usize..2 * SECTOR_SIZE as usize]
+            .iter()
+            .all(|b| *b == 0xff));
+        flash.erase_block(0).unwrap();
+        assert!(flash.memory().iter().all(|b| *b == 0xff));
+    }
+
+    #[test]
+    fn fake_bounds() {
+        let mut memory = [0xff; SECTOR_SIZE as usize];
+        let mut flash = FakeFlash::new(&mut memory, ID);
+        let mut buffer = [0; 2];
+        assert!(matches!(
+            flash.read(SECTOR_SIZE - 1, &mut buffer),
+            Err(Error::OutOfBounds)
+        ));
+        assert!(matches!(flash.write(u32::MAX, &[0]), Err(Error::OutOfBounds)));
+        assert!(matches!(flash.erase_block(0), Err(Error::OutOfBounds)));
+    }
+
+    #[test]
+    fn spi_jedec_id() {
+        let mut memory = [0xff; SECTOR_SIZE as usize];
+        let chip = chip(&mut memory);
+        let mut flash = SpiFlash::new(FakeSpi(&chip), FakeCs(&chip));
+        assert_eq!(flash.jedec_id().unwrap(), ID);
+    }
+
+    #[test]
+    fn spi_page_program_and_fast_read() {
+        let mut memory = [0xff; SECTOR_SIZE as usize];
+        let chip = chip(&mut memory);
+        let mut flash = SpiFlash::new(FakeSpi(&chip), FakeCs(&chip));
+        flash.page_program(PAGE_SIZE - 2, &[1, 2]).unwrap();
+        let mut buffer = [0; 4];
+        flash.fast_read(PAGE_SIZE - 3, &mut buffer).unwrap();
+        assert_eq!(buffer, [0xff, 1, 2, 0xff]);
+    }
+
+    #[test]
+    fn spi_page_overflow() {
+        let mut memory = [0xff; SECTOR_SIZE as usize];
+        let chip = chip(&mut memory);
+        let mut flash = SpiFlash::new(FakeSpi(&chip), FakeCs(&chip));
+        assert!(matches!(
+            flash.page_program(PAGE_SIZE - 1, &[1, 2]),
+            Err(Error::PageOverflow)
+        ));
+        // `write` splits at the page boundary instead
+        flash.write(PAGE_SIZE - 1, &[1, 2]).unwrap();
+        let mut buffer = [0; 2];
+        flash.read(PAGE_SIZE - 1, &mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2]);
+        // nothing wrapped around to the start of the first page
+        flash.read(0, &mut buffer).unwrap();
+        assert_eq!(buffer, [0xff, 0xff]);
+    }
+
+    #[test]
+    fn spi_erase() {
+        let mut memory = [0; BLOCK_SIZE as usize];
+        let chip = chip(&mut memory);
+        let mut flash = SpiFlash::new(FakeSpi(&chip), FakeCs(&chip));
+        assert!(matches!(flash.erase_sector(PAGE_SIZE), Err(Error::Unaligned)));
+        flash.erase_sector(SECTOR_SIZE).unwrap();
+        let mut buffer = [0; 2];
+        flash.read(SECTOR_SIZE - 1, &mut buffer).unwrap();
+        assert_eq!(buffer, [0, 0xff]);
+    }
+
+    #[test]
+    fn spi_bounds() {
+        let mut memory = [0xff; SECTOR_SIZE as usize];
+        let chip = chip(&mut memory);
+        let mut flash = SpiFlash::new(FakeSpi(&chip), FakeCs(&chip));
+        let mut buffer = [0; 2];
+        assert!(matches!(
+            flash.fast_read(ADDRESS_LIMIT - 1, &mut buffer),
+            Err(Error::OutOfBounds)
+        ));
+        assert!(matches!(
+            flash.page_program(ADDRESS_LIMIT, &[0]),
+            Err(Error::OutOfBounds)
+        ));
+        assert!(matches!(flash.erase_block(ADDRESS_LIMIT), Err(Error::OutOfBounds)));
+    }
+}